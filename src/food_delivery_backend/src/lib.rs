@@ -1,15 +1,221 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Nat, Principal};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Blob;
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::time::Duration;
 use std::{borrow::Cow, cell::RefCell};
 use validator::Validate;
 
 // Define type aliases for convenience
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+// Normalised item category, used as the leading part of category index keys
+type CategoryKey = Blob<64>;
+// Normalised search token, used as the leading part of search index keys
+type TokenKey = Blob<32>;
+// Normalised promo code, used as the key of the promo code index
+type PromoCodeKey = Blob<32>;
+
+// Read the caller, the clock and the canister id through 'env', and call the token ledger
+// through 'icrc', so unit tests can stand in for the replica and the ledger canister
+#[cfg(not(test))]
+mod env {
+    use candid::Principal;
+
+    pub fn caller() -> Principal {
+        ic_cdk::caller()
+    }
+
+    pub fn time() -> u64 {
+        ic_cdk::api::time()
+    }
+
+    pub fn id() -> Principal {
+        ic_cdk::id()
+    }
+}
+
+#[cfg(not(test))]
+mod icrc {
+    use super::{TransferArg, TransferError, TransferFromArgs, TransferFromError};
+    use candid::{Nat, Principal};
+    use ic_cdk::api::call::CallResult;
+
+    pub async fn icrc2_transfer_from(
+        ledger: Principal,
+        args: TransferFromArgs,
+    ) -> CallResult<(Result<Nat, TransferFromError>,)> {
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,)).await
+    }
+
+    pub async fn icrc1_transfer(
+        ledger: Principal,
+        args: TransferArg,
+    ) -> CallResult<(Result<Nat, TransferError>,)> {
+        ic_cdk::call(ledger, "icrc1_transfer", (args,)).await
+    }
+}
+
+#[cfg(test)]
+mod tests;
+#[cfg(test)]
+use tests::{env, icrc};
+
+// Keep at most this many location points per order, dropping the oldest first
+const MAX_ROUTE_POINTS: u64 = 500;
+// Keep the route of a completed order for a day before pruning it
+const LOCATION_RETENTION_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const LOCATION_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Prune the routes of at most this many completed orders per tick
+const LOCATION_PRUNE_BATCH: usize = 20;
+
+// Preparation time assumed for items that do not specify one
+const DEFAULT_PREP_MINUTES: u32 = 15;
+// Average speed assumed while no courier is assigned
+const DEFAULT_SPEED_KMH: f64 = 20.0;
+const EARTH_RADIUS_KM: f64 = 6371.0;
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+
+// Largest quantity of a single item accepted in one order until an admin changes it
+const DEFAULT_MAX_ITEM_QUANTITY: u64 = 50;
+// Bounds that keep an 'Order' with its line snapshots within 'Order::MAX_SIZE'
+const MAX_ORDER_LINES: usize = 30;
+const MAX_LINE_NAME_CHARS: usize = 64;
+// Bound that keeps an 'OrderEvent' with its note within 'OrderEvent::MAX_SIZE'
+const MAX_NOTE_CHARS: u64 = 160;
+// Bound that keeps a 'Review' with its comment within 'Review::MAX_SIZE'
+const MAX_REVIEW_COMMENT_CHARS: u64 = 200;
+// Bounds that keep a 'Restaurant' within 'Restaurant::MAX_SIZE', even in four-byte characters;
+// two opening hours entries a day allow for a lunch break
+const MAX_RESTAURANT_NAME_CHARS: u64 = 64;
+const MAX_RESTAURANT_ADDRESS_CHARS: u64 = 100;
+const MAX_OPENING_HOURS: u64 = 14;
+// Bound that keeps a 'Courier' with its name within 'Courier::MAX_SIZE'
+const MAX_COURIER_NAME_CHARS: u64 = 64;
+// Bounds that keep a 'Client' within 'Client::MAX_SIZE', even in four-byte characters, next to
+// its recent order ids
+const MAX_CLIENT_NAME_CHARS: u64 = 32;
+const MAX_CLIENT_ADDRESS_CHARS: u64 = 64;
+const MAX_CLIENT_PHONE_CHARS: u64 = 16;
+const MAX_CLIENT_EMAIL_CHARS: u64 = 40;
+// Number of recent order ids kept on a 'Client' record; the full list lives in an index
+const MAX_CLIENT_ORDER_IDS: usize = 32;
+// Minutes after placing an order during which its client may cancel it, until an admin changes it
+const DEFAULT_CANCELLATION_WINDOW_MINUTES: u64 = 5;
+// Share of a paid order owed to its courier, in basis points, until an admin changes it
+const DEFAULT_COURIER_PAYOUT_BPS: u32 = 1_000;
+const MAX_BPS: u32 = 10_000;
+// Layout of the stored data written by this version; upgrades from an earlier layout rewrite and
+// reindex what is stored, so it goes up whenever a migration is added
+const STORAGE_LAYOUT_VERSION: u32 = 1;
+// Page size used when a 'Page' leaves the limit at 0, and the largest size served,
+// keeping list replies well below the 2MB reply limit
+const DEFAULT_PAGE_LIMIT: u32 = 50;
+const MAX_PAGE_LIMIT: u32 = 100;
+// Largest 'total' counted by a v2 list query that has no maintained count, so a query never
+// walks more than this many index entries
+const MAX_LIST_TOTAL: usize = 1_000;
+// Bounds that keep an 'Item' within 'Item::MAX_SIZE'; text is bounded in UTF-8 bytes, as an
+// item with every other field at its largest already takes about 290 bytes
+const MAX_DIETARY_TAGS: usize = 8;
+const MAX_DIETARY_TAG_BYTES: usize = 24;
+const MAX_ITEM_NAME_BYTES: usize = 64;
+const MAX_ITEM_CATEGORY_BYTES: usize = 64;
+const MAX_ITEM_DESCRIPTION_BYTES: usize = 300;
+// Prior used for Bayesian rating averages: items start out as if they had this many reviews
+// of this rating, so a single five-star review does not outrank a long track record
+const RATING_PRIOR_MEAN: f64 = 3.0;
+const RATING_PRIOR_WEIGHT: f64 = 5.0;
+// Largest number of entries in each pricing rule table
+const MAX_PRICING_TABLE_ENTRIES: usize = 32;
+// Bounds that keep a 'Promotion' within 'Promotion::MAX_SIZE'
+const MAX_PROMO_CODE_CHARS: usize = 32;
+const MAX_PROMOTION_CATEGORIES: usize = 8;
+const MAX_CATEGORY_CHARS: usize = 64;
+
+// Define an enum for the currencies prices can be set in
+#[derive(
+    candid::CandidType,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Default,
+)]
+enum Currency {
+    #[default]
+    Usd,
+    Eur,
+    Gbp,
+    Icp,
+    CkUsdc,
+}
+
+// Define a struct for an amount of money in the smallest unit of its currency, such as cents or e8s
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default,
+)]
+struct Money {
+    amount_minor: u64,
+    currency: Currency,
+}
+
+impl Money {
+    fn new(amount_minor: u64, currency: Currency) -> Self {
+        Money {
+            amount_minor,
+            currency,
+        }
+    }
+
+    fn zero(currency: Currency) -> Self {
+        Money::new(0, currency)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.amount_minor == 0
+    }
+
+    // Sum of two amounts, or none if their currencies differ or the sum overflows
+    fn checked_add(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        let amount_minor = self.amount_minor.checked_add(other.amount_minor)?;
+        Some(Money::new(amount_minor, self.currency))
+    }
+
+    // Difference of two amounts, or none if their currencies differ or it would be negative
+    fn checked_sub(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        let amount_minor = self.amount_minor.checked_sub(other.amount_minor)?;
+        Some(Money::new(amount_minor, self.currency))
+    }
+
+    fn checked_mul(self, factor: u64) -> Option<Money> {
+        let amount_minor = self.amount_minor.checked_mul(factor)?;
+        Some(Money::new(amount_minor, self.currency))
+    }
+
+    // Share of the amount in basis points, rounded down
+    fn bps_share(self, bps: u32) -> Money {
+        let share = self.amount_minor as u128 * bps.min(MAX_BPS) as u128 / MAX_BPS as u128;
+        Money::new(share as u64, self.currency)
+    }
+}
 
 // Define a struct for the 'Client'
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -19,8 +225,15 @@ struct Client {
     address: String,
     phone: String,
     email: String,
+    // Only set for clients created before principal identity, until they claim their record
     password: String,
+    principal: Option<Principal>,
+    // Most recent orders first, bounded by 'MAX_CLIENT_ORDER_IDS'
     order_ids: Vec<u64>,
+    // Time of the last change in nanoseconds; not set for clients created before updates existed
+    updated_at: Option<u64>,
+    // Time the account was deleted; the personal data of deleted clients is wiped
+    deleted_at: Option<u64>,
 }
 
 // Define a struct for the 'Order'
@@ -28,233 +241,4415 @@ struct Client {
 struct Order {
     id: u64,
     client_id: u64,
-    items: HashMap<u64, u64>,
-    total: u64,
-    status: String,
+    lines: Vec<OrderLine>,
+    total: Money,
+    status: OrderStatus,
     delivered: bool,
+    restaurant_id: Option<u64>,
+    assignment: Option<Assignment>,
+    delivery_location: Option<Location>,
+    // Set once the order is cancelled
+    cancellation: Option<Cancellation>,
+    // Amount collected from the client so far; not set until payment is captured
+    amount_captured: Option<Money>,
+    // How 'total' was made up; not set for orders placed before fees and taxes, whose total is
+    // the sum of their lines
+    price_breakdown: Option<PriceBreakdown>,
+    // Promotion redeemed by the order, if a promo code was given
+    promotion_id: Option<u64>,
 }
 
-// Define a struct for the 'Review'
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct Review {
+// Define a struct for the itemised price of an 'Order'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PriceBreakdown {
+    // Sum of the line totals
+    subtotal: Money,
+    delivery_fee: Money,
+    // Distance from the restaurant the delivery fee was charged for; not set when the
+    // restaurant or the delivery location is unknown
+    delivery_distance_km: Option<f64>,
+    // Surcharge on orders below the minimum subtotal
+    small_order_fee: Money,
+    service_fee: Money,
+    tax: Money,
+    tip: Money,
+    discount: Money,
+    // Part of the discount that waives the delivery fee; not set for orders priced before
+    // it was broken out
+    delivery_discount: Option<Money>,
+    // Subtotal plus fees, tax and tip, less discounts
+    total: Money,
+}
+
+impl PriceBreakdown {
+    // Part of the discount that waives the delivery fee, taking it from the promotion of an
+    // order priced before it was broken out
+    fn delivery_discount(&self, promotion_id: Option<u64>) -> Money {
+        if let Some(delivery_discount) = self.delivery_discount {
+            return delivery_discount;
+        }
+        let free_delivery = promotion_id
+            .and_then(|id| PROMOTION_STORAGE.with(|s| s.borrow().get(&id)))
+            .is_some_and(|promotion| matches!(promotion.discount, Discount::FreeDelivery));
+        match free_delivery {
+            true => self.discount,
+            false => Money::zero(self.discount.currency),
+        }
+    }
+}
+
+// Define a struct for orders stored before amounts carried a currency
+#[derive(candid::CandidType, Deserialize)]
+struct PlainAmountOrder {
     id: u64,
     client_id: u64,
-    item_id: u64,
-    rating: u64,
-    comment: String,
+    lines: Vec<PlainAmountOrderLine>,
+    total: u64,
+    status: OrderStatus,
+    delivered: bool,
+    restaurant_id: Option<u64>,
+    assignment: Option<Assignment>,
+    delivery_location: Option<Location>,
+    cancellation: Option<Cancellation>,
+    amount_captured: Option<u64>,
 }
 
-// Define a struct for the 'Item'
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct Item {
-    id: u64,
+#[derive(candid::CandidType, Deserialize)]
+struct PlainAmountOrderLine {
+    item_id: u64,
     name: String,
-    description: String,
-    price: u64,
-    category: String,
+    unit_price: u64,
+    quantity: u64,
+    line_total: u64,
 }
 
-// Implement the 'Storable' trait for the 'Client', 'Order', 'Review' and 'Item' structs
-impl Storable for Client {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-    // Conversion from bytes
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+impl From<PlainAmountOrder> for Order {
+    fn from(plain: PlainAmountOrder) -> Self {
+        let currency = base_currency();
+        Order {
+            id: plain.id,
+            client_id: plain.client_id,
+            lines: plain
+                .lines
+                .into_iter()
+                .map(|line| OrderLine {
+                    item_id: line.item_id,
+                    name: line.name,
+                    unit_price: Money::new(line.unit_price, currency),
+                    quantity: line.quantity,
+                    line_total: Money::new(line.line_total, currency),
+                })
+                .collect(),
+            total: Money::new(plain.total, currency),
+            status: plain.status,
+            delivered: plain.delivered,
+            restaurant_id: plain.restaurant_id,
+            assignment: plain.assignment,
+            delivery_location: plain.delivery_location,
+            cancellation: plain.cancellation,
+            amount_captured: plain
+                .amount_captured
+                .map(|amount| Money::new(amount, currency)),
+            price_breakdown: None,
+            promotion_id: None,
+        }
     }
 }
 
-impl Storable for Order {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Define a struct for who cancelled an 'Order', when and why
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Cancellation {
+    reason: CancellationReason,
+    note: Option<String>,
+    cancelled_by: Principal,
+    cancelled_at: u64,
+}
+
+// Define an enum for the reasons an 'Order' can be cancelled
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum CancellationReason {
+    ChangedMind,
+    OrderedByMistake,
+    TakingTooLong,
+    RestaurantClosed,
+    ItemsUnavailable,
+    NoCourierAvailable,
+    DeliveryFailed,
+    SuspectedFraud,
+    Other,
+}
+
+// Define a struct for money owed back to a client for a cancelled 'Order'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Refund {
+    order_id: u64,
+    client_id: u64,
+    amount: Money,
+    reason: CancellationReason,
+    created_at: u64,
+    // Not set until the money has been paid back
+    settled_at: Option<u64>,
+}
+
+// Define a struct for refunds stored before amounts carried a currency
+#[derive(candid::CandidType, Deserialize)]
+struct PlainAmountRefund {
+    order_id: u64,
+    client_id: u64,
+    amount: u64,
+    reason: CancellationReason,
+    created_at: u64,
+    settled_at: Option<u64>,
+}
+
+impl From<PlainAmountRefund> for Refund {
+    fn from(plain: PlainAmountRefund) -> Self {
+        Refund {
+            order_id: plain.order_id,
+            client_id: plain.client_id,
+            amount: Money::new(plain.amount, base_currency()),
+            reason: plain.reason,
+            created_at: plain.created_at,
+            settled_at: plain.settled_at,
+        }
     }
-    // Conversion from bytes
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+}
+
+// Define a struct for an entry of the internal payment ledger of an 'Order'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LedgerEntry {
+    order_id: u64,
+    kind: LedgerEntryKind,
+    // The principal paying for a payment, or receiving a payout or refund
+    counterparty: Principal,
+    amount: Money,
+    // Block of the token ledger transfer; not set for payouts that are only owed
+    block_index: Option<Nat>,
+    created_at: u64,
+}
+
+// Define a struct for ledger entries stored before amounts carried a currency
+#[derive(candid::CandidType, Deserialize)]
+struct PlainAmountLedgerEntry {
+    order_id: u64,
+    kind: LedgerEntryKind,
+    counterparty: Principal,
+    amount: u64,
+    block_index: Option<Nat>,
+    created_at: u64,
+}
+
+impl From<PlainAmountLedgerEntry> for LedgerEntry {
+    fn from(plain: PlainAmountLedgerEntry) -> Self {
+        LedgerEntry {
+            order_id: plain.order_id,
+            kind: plain.kind,
+            counterparty: plain.counterparty,
+            amount: Money::new(plain.amount, base_currency()),
+            block_index: plain.block_index,
+            created_at: plain.created_at,
+        }
     }
 }
 
-impl Storable for Review {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum LedgerEntryKind {
+    Payment,
+    RestaurantPayout,
+    CourierPayout,
+    Refund,
+}
+
+// Define structs for the parts of the ICRC-1 and ICRC-2 token ledger interface in use
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Define a struct for an 'Item' of an 'Order', priced at the time the order was placed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderLine {
+    item_id: u64,
+    name: String,
+    unit_price: Money,
+    quantity: u64,
+    line_total: Money,
+}
+
+impl OrderLine {
+    // Snapshot an item at its current name and price
+    fn snapshot(item: &Item, quantity: u64) -> Option<Self> {
+        Some(OrderLine {
+            item_id: item.id,
+            name: item.name.chars().take(MAX_LINE_NAME_CHARS).collect(),
+            unit_price: item.price,
+            quantity,
+            line_total: item.price.checked_mul(quantity)?,
+        })
     }
-    // Conversion from bytes
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+
+    // Snapshot an item of a stored order from the current menu, if it still exists
+    fn migrate(item_id: u64, quantity: u64) -> Self {
+        ITEM_STORAGE
+            .with(|s| s.borrow().get(&item_id))
+            .and_then(|item| OrderLine::snapshot(&item, quantity))
+            .unwrap_or(OrderLine {
+                item_id,
+                name: String::new(),
+                unit_price: Money::zero(base_currency()),
+                quantity,
+                line_total: Money::zero(base_currency()),
+            })
     }
 }
 
-impl Storable for Item {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Define a struct for the assignment of a 'Courier' to an 'Order'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Assignment {
+    courier_id: u64,
+    accepted: bool,
+    assigned_at: u64,
+}
+
+// Define an enum for the 'Order' lifecycle states
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Debug,
+)]
+enum OrderStatus {
+    #[default]
+    Placed,
+    Accepted,
+    Preparing,
+    ReadyForPickup,
+    PickedUp,
+    InTransit,
+    Delivered,
+    Cancelled,
+    Refunded,
+    // Payment was verified on the ledger; kept last so stored status discriminants stay valid
+    Paid,
+}
+
+impl OrderStatus {
+    // Check if the order has reached a state it can only leave through a refund
+    fn is_final(self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Delivered | OrderStatus::Cancelled | OrderStatus::Refunded
+        )
     }
-    // Conversion from bytes
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+
+    // Check the transition table to see if an order may move from 'self' to 'next'
+    fn can_transition_to(self, next: OrderStatus) -> bool {
+        use OrderStatus::*;
+        matches!(
+            (self, next),
+            (Placed, Accepted)
+                | (Placed, Paid)
+                | (Placed, Cancelled)
+                | (Paid, Accepted)
+                | (Paid, Cancelled)
+                | (Accepted, Preparing)
+                | (Accepted, Cancelled)
+                | (Preparing, ReadyForPickup)
+                | (Preparing, Cancelled)
+                | (ReadyForPickup, PickedUp)
+                | (ReadyForPickup, Cancelled)
+                | (PickedUp, InTransit)
+                | (PickedUp, Delivered)
+                | (PickedUp, Cancelled)
+                | (InTransit, Delivered)
+                | (InTransit, Cancelled)
+                | (Delivered, Refunded)
+                | (Cancelled, Refunded)
+        )
+    }
+
+    // Map a free-text status stored before the state machine existed onto a variant
+    fn from_legacy(status: &str, delivered: bool) -> Self {
+        let status = status.to_lowercase();
+        if delivered {
+            OrderStatus::Delivered
+        } else if status.contains("refund") {
+            OrderStatus::Refunded
+        } else if status.contains("cancel") {
+            OrderStatus::Cancelled
+        } else if status.contains("transit") || status.contains("on the way") {
+            OrderStatus::InTransit
+        } else if status.contains("picked") {
+            OrderStatus::PickedUp
+        } else if status.contains("ready") {
+            OrderStatus::ReadyForPickup
+        } else if status.contains("prepar") || status.contains("cooking") {
+            OrderStatus::Preparing
+        } else if status.contains("accept") || status.contains("confirm") {
+            OrderStatus::Accepted
+        } else {
+            OrderStatus::Placed
+        }
     }
 }
 
-// Implement the 'BoundedStorable' trait for the 'Client', 'Order', 'Review' and 'Item' structs
-impl BoundedStorable for Client {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+impl Order {
+    // Move the order to a new state, keeping 'delivered' derived from it
+    fn set_status(&mut self, status: OrderStatus) -> Result<(), Error> {
+        if !self.status.can_transition_to(status) {
+            return Err(Error::InvalidTransition {
+                msg: format!(
+                    "order id: {} cannot move from {:?} to {:?}",
+                    self.id, self.status, status
+                ),
+            });
+        }
+        self.status = status;
+        self.delivered = status == OrderStatus::Delivered;
+        Ok(())
+    }
 }
 
-impl BoundedStorable for Order {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Define an enum for the roles a principal can be granted
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum Role {
+    Admin,
+    RestaurantStaff,
+    Courier,
+    Customer,
 }
 
-impl BoundedStorable for Review {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Define a struct for the set of roles granted to a principal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RoleSet {
+    roles: Vec<Role>,
 }
 
-impl BoundedStorable for Item {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Define a struct for an entry in an 'Order' status timeline
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderEvent {
+    order_id: u64,
+    status: OrderStatus,
+    timestamp: u64,
+    actor: Principal,
+    note: Option<String>,
 }
 
-// Define thread-local static variables for memory management and storage
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+// Define a struct for orders stored before 'status' became an 'OrderStatus'
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyOrder {
+    id: u64,
+    client_id: u64,
+    items: HashMap<u64, u64>,
+    total: u64,
+    status: String,
+    delivered: bool,
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+// Define a struct for orders stored before line item snapshots, holding item id to quantity
+#[derive(candid::CandidType, Deserialize)]
+struct UnpricedOrder {
+    id: u64,
+    client_id: u64,
+    items: HashMap<u64, u64>,
+    total: u64,
+    status: OrderStatus,
+    delivered: bool,
+    restaurant_id: Option<u64>,
+    assignment: Option<Assignment>,
+    delivery_location: Option<Location>,
+}
 
-    static CLIENT_STORAGE: RefCell<StableBTreeMap<u64, Client, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+impl From<LegacyOrder> for UnpricedOrder {
+    fn from(legacy: LegacyOrder) -> Self {
+        let status = OrderStatus::from_legacy(&legacy.status, legacy.delivered);
+        UnpricedOrder {
+            id: legacy.id,
+            client_id: legacy.client_id,
+            items: legacy.items,
+            total: legacy.total,
+            status,
+            delivered: status == OrderStatus::Delivered,
+            restaurant_id: None,
+            assignment: None,
+            delivery_location: None,
+        }
+    }
+}
 
-    static ORDER_STORAGE: RefCell<StableBTreeMap<u64, Order, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-    ));
+impl From<UnpricedOrder> for Order {
+    fn from(unpriced: UnpricedOrder) -> Self {
+        let mut items: Vec<(u64, u64)> = unpriced.items.into_iter().collect();
+        items.sort();
+        Order {
+            id: unpriced.id,
+            client_id: unpriced.client_id,
+            lines: items
+                .into_iter()
+                .map(|(item_id, quantity)| OrderLine::migrate(item_id, quantity))
+                .collect(),
+            total: Money::new(unpriced.total, base_currency()),
+            status: unpriced.status,
+            delivered: unpriced.delivered,
+            restaurant_id: unpriced.restaurant_id,
+            assignment: unpriced.assignment,
+            delivery_location: unpriced.delivery_location,
+            cancellation: None,
+            amount_captured: None,
+            price_breakdown: None,
+            promotion_id: None,
+        }
+    }
+}
 
-    static REVIEW_STORAGE: RefCell<StableBTreeMap<u64, Review, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-    ));
+// Define a wrapper for the raw bytes of orders in the storage used before line item snapshots
+struct StoredOrderBytes(Vec<u8>);
 
-    static ITEM_STORAGE: RefCell<StableBTreeMap<u64, Item, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
-    ));
+impl StoredOrderBytes {
+    // Decode either earlier layout into the current 'Order'
+    fn migrate(&self) -> Order {
+        Decode!(&self.0, UnpricedOrder)
+            .unwrap_or_else(|_| Decode!(&self.0, LegacyOrder).unwrap().into())
+            .into()
+    }
 }
 
-// Define structs for payload data (used in update calls)
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
-struct ClientPayload {
-    #[validate(length(min = 2))]
-    name: String,
-    #[validate(length(min = 4))]
-    address: String,
-    phone: String,
-    email: String,
-    password: String,
-}
+// Define a wrapper for the raw bytes of a stored 'V', to rewrite values stored in an earlier
+// layout on upgrade
+struct RawBytes<V>(Vec<u8>, PhantomData<V>);
 
+// Define a struct for a discount campaign redeemed with a promo code when placing an 'Order'
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct OrderPayload {
-    client_id: u64,
-    items: Vec<OrderItem>,
+struct Promotion {
+    id: u64,
+    // Matched ignoring case and surrounding whitespace
+    code: String,
+    discount: Discount,
+    // Orders need a subtotal of at least this much, in the same currency
+    min_subtotal: Option<Money>,
+    // Validity window in nanoseconds since the epoch; open-ended while 'valid_until' is not set
+    valid_from: u64,
+    valid_until: Option<u64>,
+    max_redemptions: Option<u64>,
+    max_redemptions_per_client: Option<u64>,
+    // Only for clients without an earlier order that went ahead
+    first_order_only: bool,
+    // Categories of the discounted lines and restaurants the code can be used at; empty lists
+    // do not restrict the promotion
+    categories: Vec<String>,
+    restaurant_ids: Vec<u64>,
+    // Orders placed with the code, less the ones cancelled since
+    redemptions: u64,
+    created_at: u64,
+    updated_at: u64,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct OrderItem {
-    item_id: u64,
-    quantity: u64,
+impl Promotion {
+    fn covers_category(&self, category: &str) -> bool {
+        self.categories.is_empty()
+            || self
+                .categories
+                .iter()
+                .any(|covered| category_key(covered) == category_key(category))
+    }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct ReviewPayload {
+// Define an enum for what a 'Promotion' takes off an order
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum Discount {
+    // Share of the discounted lines, in basis points
+    Percentage { bps: u32 },
+    // Amount off the discounted lines, up to their total
+    FixedAmount { amount: Money },
+    // The delivery fee is waived
+    FreeDelivery,
+}
+
+// Define a struct for the 'Review'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Review {
+    id: u64,
+    // Not set for reviews written before reviews recorded their author, which were all
+    // stored under client id 0
+    client_id: Option<u64>,
     item_id: u64,
     rating: u64,
     comment: String,
+    // The delivered order the item was bought in; not set for reviews written before
+    // verified purchases were required
+    order_id: Option<u64>,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
-struct ItemPayload {
-    #[validate(length(min = 2))]
+// Define a struct for the 'Item'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Item {
+    id: u64,
     name: String,
-    #[validate(length(min = 4))]
     description: String,
-    price: u64,
+    price: Money,
     category: String,
+    // Not set for items created before restaurants existed
+    restaurant_id: Option<u64>,
+    // Not set for items created before ETA estimation existed
+    prep_minutes: Option<u32>,
+    // Lowercase tags such as "vegan" or "gluten-free"; not set for items created before search
+    dietary_tags: Option<Vec<String>>,
+    // Time of the last change in nanoseconds; not set for items created before updates existed
+    updated_at: Option<u64>,
+    // Not set for items created before availability existed, which are available
+    availability: Option<ItemAvailability>,
+    // Time the item was removed from the menu; archived items are kept for order history
+    archived_at: Option<u64>,
+    // Not set until the item is first reviewed
+    rating: Option<RatingStats>,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct ClientResponse {
-    id: u64,
-    name: String,
-    address: String,
-    phone: String,
-    email: String,
-    order_ids: Vec<u64>,
+impl Item {
+    fn availability(&self) -> ItemAvailability {
+        self.availability.unwrap_or(ItemAvailability::Available)
+    }
+
+    fn is_archived(&self) -> bool {
+        self.archived_at.is_some()
+    }
+
+    // Whether the item is shown on menus and in search results
+    fn is_listed(&self) -> bool {
+        !self.is_archived() && self.availability() != ItemAvailability::Hidden
+    }
+
+    // Whether the item can be added to a new order
+    fn is_orderable(&self) -> bool {
+        !self.is_archived() && self.availability() == ItemAvailability::Available
+    }
+}
+
+// Define a struct for items stored before prices carried a currency
+#[derive(candid::CandidType, Deserialize)]
+struct PlainPriceItem {
+    id: u64,
+    name: String,
+    description: String,
+    price: u64,
+    category: String,
+    restaurant_id: Option<u64>,
+    prep_minutes: Option<u32>,
+    dietary_tags: Option<Vec<String>>,
+    updated_at: Option<u64>,
+    availability: Option<ItemAvailability>,
+    archived_at: Option<u64>,
+    rating: Option<RatingStats>,
+}
+
+impl From<PlainPriceItem> for Item {
+    fn from(plain: PlainPriceItem) -> Self {
+        Item {
+            id: plain.id,
+            name: plain.name,
+            description: plain.description,
+            price: Money::new(plain.price, base_currency()),
+            category: plain.category,
+            restaurant_id: plain.restaurant_id,
+            prep_minutes: plain.prep_minutes,
+            dietary_tags: plain.dietary_tags,
+            updated_at: plain.updated_at,
+            availability: plain.availability,
+            archived_at: plain.archived_at,
+            rating: plain.rating,
+        }
+    }
+}
+
+// Define an enum for whether a menu 'Item' can be seen and ordered
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum ItemAvailability {
+    Available,
+    // Listed on menus but cannot be ordered
+    SoldOut,
+    // Neither listed nor orderable
+    Hidden,
+}
+
+// Define a struct for the 'Restaurant' selling menu items
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Restaurant {
+    id: u64,
+    name: String,
+    address: String,
+    latitude: f64,
+    longitude: f64,
+    opening_hours: Vec<OpeningHours>,
+    owner: Principal,
+    active: bool,
+    // Totals over the reviews of all items of the restaurant; not set until the first review
+    rating: Option<RatingStats>,
+}
+
+// Define a struct for the running rating totals of an item or restaurant
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct RatingStats {
+    count: u64,
+    sum: u64,
+    // Number of reviews per star, one star first
+    histogram: [u64; 5],
+}
+
+impl RatingStats {
+    fn add(&mut self, rating: u64) {
+        let rating = rating.clamp(1, 5);
+        self.count += 1;
+        self.sum += rating;
+        self.histogram[rating as usize - 1] += 1;
+    }
+
+    fn remove(&mut self, rating: u64) {
+        let rating = rating.clamp(1, 5);
+        self.count = self.count.saturating_sub(1);
+        self.sum = self.sum.saturating_sub(rating);
+        let bucket = &mut self.histogram[rating as usize - 1];
+        *bucket = bucket.saturating_sub(1);
+    }
+
+    fn average(&self) -> Option<f64> {
+        match self.count {
+            0 => None,
+            count => Some(self.sum as f64 / count as f64),
+        }
+    }
+
+    fn bayesian_average(&self) -> f64 {
+        (RATING_PRIOR_MEAN * RATING_PRIOR_WEIGHT + self.sum as f64)
+            / (RATING_PRIOR_WEIGHT + self.count as f64)
+    }
+}
+
+// Define a struct for the rating statistics of an item
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RatingSummary {
+    item_id: u64,
+    count: u64,
+    sum: u64,
+    histogram: [u64; 5],
+    average: Option<f64>,
+    bayesian_average: f64,
+}
+
+impl RatingSummary {
+    fn new(item_id: u64, stats: RatingStats) -> Self {
+        RatingSummary {
+            item_id,
+            count: stats.count,
+            sum: stats.sum,
+            histogram: stats.histogram,
+            average: stats.average(),
+            bayesian_average: stats.bayesian_average(),
+        }
+    }
+}
+
+// Define a struct for the 'Courier' delivering orders
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Courier {
+    id: u64,
+    principal: Principal,
+    name: String,
+    vehicle: VehicleType,
+    available: bool,
+    location: Option<Location>,
+}
+
+// Define an enum for the vehicle a 'Courier' delivers with
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum VehicleType {
+    OnFoot,
+    Bicycle,
+    Scooter,
+    Motorbike,
+    Car,
+}
+
+impl VehicleType {
+    // Average speed in an urban area, including stops
+    fn average_speed_kmh(self) -> f64 {
+        match self {
+            VehicleType::OnFoot => 5.0,
+            VehicleType::Bicycle => 15.0,
+            VehicleType::Scooter => 22.0,
+            VehicleType::Motorbike => 28.0,
+            VehicleType::Car => 25.0,
+        }
+    }
+}
+
+// Define a struct for a geographic position
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug, Validate)]
+struct Location {
+    #[validate(range(min = -90.0, max = 90.0))]
+    latitude: f64,
+    #[validate(range(min = -180.0, max = 180.0))]
+    longitude: f64,
+}
+
+// Define a struct for the estimated arrival of an 'Order'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderEta {
+    order_id: u64,
+    estimated_arrival: u64,
+    remaining_distance_km: f64,
+    computed_at: u64,
+}
+
+impl Location {
+    // Great-circle distance to another location in kilometers, using the haversine formula
+    fn distance_km(&self, other: &Location) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+    }
+}
+
+// Define a struct for a location reported by a 'Courier' while delivering an 'Order'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LocationPoint {
+    latitude: f64,
+    longitude: f64,
+    // Radius of uncertainty in meters
+    accuracy: f64,
+    timestamp: u64,
+}
+
+// Define a struct for the opening hours of a 'Restaurant' on one day of the week
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OpeningHours {
+    // 0 is Monday, 6 is Sunday
+    day: u8,
+    // Minutes after midnight UTC
+    opens_at: u16,
+    closes_at: u16,
+}
+
+// Define a struct for the canister-wide settings managed by admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Config {
+    max_item_quantity: u64,
+    // Not set in settings stored before cancellations existed, which use the default
+    cancellation_window_minutes: Option<u64>,
+    // ICRC-2 ledger orders are paid through; orders need no payment while it is not set
+    ledger_canister_id: Option<Principal>,
+    // Not set in settings stored before payments existed, which use the default
+    courier_payout_bps: Option<u32>,
+    // Currency of amounts stored before currencies existed, and of payments through the ledger;
+    // chosen at install and fixed afterwards
+    base_currency: Option<Currency>,
+    // Layout of the stored data, kept by the canister; not set in settings stored before layout
+    // versions existed, whose data is migrated on the next upgrade
+    layout_version: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_item_quantity: DEFAULT_MAX_ITEM_QUANTITY,
+            cancellation_window_minutes: Some(DEFAULT_CANCELLATION_WINDOW_MINUTES),
+            ledger_canister_id: None,
+            courier_payout_bps: Some(DEFAULT_COURIER_PAYOUT_BPS),
+            base_currency: None,
+            layout_version: None,
+        }
+    }
+}
+
+impl Config {
+    fn cancellation_window_nanos(&self) -> u64 {
+        self.cancellation_window_minutes
+            .unwrap_or(DEFAULT_CANCELLATION_WINDOW_MINUTES)
+            .saturating_mul(NANOS_PER_MINUTE)
+    }
+}
+
+// Define a struct for the rule tables used to price an 'Order' beyond its line totals
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PricingRules {
+    // Delivery fees by distance from the restaurant, per currency; orders are charged the
+    // shortest band covering their distance
+    delivery_fee_bands: Vec<DeliveryFeeBand>,
+    // Surcharges on orders below a minimum subtotal, at most one per currency
+    small_order_fees: Vec<SmallOrderFee>,
+    // Share of the subtotal charged as a service fee, in basis points
+    service_fee_bps: u32,
+    // Tax on line totals in basis points, used for categories without a rate of their own
+    default_tax_bps: u32,
+    category_tax_rates: Vec<CategoryTaxRate>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DeliveryFeeBand {
+    max_distance_km: f64,
+    fee: Money,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SmallOrderFee {
+    min_subtotal: Money,
+    fee: Money,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CategoryTaxRate {
+    category: String,
+    rate_bps: u32,
+}
+
+impl PricingRules {
+    fn tax_bps(&self, category: &str) -> u32 {
+        let category = category_key(category);
+        self.category_tax_rates
+            .iter()
+            .find(|rate| category_key(&rate.category) == category)
+            .map_or(self.default_tax_bps, |rate| rate.rate_bps)
+    }
+
+    // Fee of the shortest band in the currency covering the distance; an unknown distance is
+    // charged the widest band, and no bands in the currency mean free delivery
+    fn delivery_fee(&self, currency: Currency, distance_km: Option<f64>) -> Result<Money, Error> {
+        let mut bands: Vec<&DeliveryFeeBand> = self
+            .delivery_fee_bands
+            .iter()
+            .filter(|band| band.fee.currency == currency)
+            .collect();
+        bands.sort_by(|a, b| a.max_distance_km.total_cmp(&b.max_distance_km));
+        let band = match (distance_km, bands.last()) {
+            (_, None) => return Ok(Money::zero(currency)),
+            (None, Some(widest)) => widest,
+            (Some(distance_km), Some(_)) => bands
+                .iter()
+                .find(|band| distance_km <= band.max_distance_km)
+                .ok_or_else(|| Error::InvalidPayload {
+                    msg: format!(
+                        "the delivery location is {:.1} km from the restaurant, outside the delivery area",
+                        distance_km
+                    ),
+                })?,
+        };
+        Ok(band.fee)
+    }
+
+    fn small_order_fee(&self, subtotal: Money) -> Money {
+        self.small_order_fees
+            .iter()
+            .find(|rule| {
+                rule.min_subtotal.currency == subtotal.currency
+                    && subtotal.amount_minor < rule.min_subtotal.amount_minor
+            })
+            .map_or(Money::zero(subtotal.currency), |rule| rule.fee)
+    }
+}
+
+// Check that the rule tables are bounded, consistent and use rates of at most 100%
+fn validate_pricing_rules(rules: &PricingRules) -> Result<(), Error> {
+    let invalid = |msg: String| Err(Error::InvalidPayload { msg });
+    if rules.delivery_fee_bands.len() > MAX_PRICING_TABLE_ENTRIES
+        || rules.small_order_fees.len() > MAX_PRICING_TABLE_ENTRIES
+        || rules.category_tax_rates.len() > MAX_PRICING_TABLE_ENTRIES
+    {
+        return invalid(format!(
+            "pricing tables cannot have more than {} entries",
+            MAX_PRICING_TABLE_ENTRIES
+        ));
+    }
+    if rules.service_fee_bps > MAX_BPS || rules.default_tax_bps > MAX_BPS {
+        return invalid(format!("rates cannot be above {} basis points", MAX_BPS));
+    }
+
+    let mut bands: HashSet<(Currency, u64)> = HashSet::new();
+    for band in &rules.delivery_fee_bands {
+        require_base_currency(&band.fee, "a delivery fee")?;
+        if !band.max_distance_km.is_finite() || band.max_distance_km <= 0.0 {
+            return invalid("max_distance_km must be a positive distance".to_string());
+        }
+        if !bands.insert((band.fee.currency, band.max_distance_km.to_bits())) {
+            return invalid(format!(
+                "there is more than one {:?} delivery band of {} km",
+                band.fee.currency, band.max_distance_km
+            ));
+        }
+    }
+
+    let mut currencies: HashSet<Currency> = HashSet::new();
+    for rule in &rules.small_order_fees {
+        require_base_currency(&rule.fee, "a small order fee")?;
+        if rule.fee.currency != rule.min_subtotal.currency {
+            return invalid("a small order fee must use the currency of its minimum".to_string());
+        }
+        if !currencies.insert(rule.fee.currency) {
+            return invalid(format!(
+                "there is more than one {:?} small order fee",
+                rule.fee.currency
+            ));
+        }
+    }
+
+    let mut categories: Vec<CategoryKey> = Vec::new();
+    for rate in &rules.category_tax_rates {
+        if rate.category.trim().is_empty() {
+            return invalid("a tax rate needs a category".to_string());
+        }
+        if rate.rate_bps > MAX_BPS {
+            return invalid(format!("rates cannot be above {} basis points", MAX_BPS));
+        }
+        let category = category_key(&rate.category);
+        if categories.contains(&category) {
+            return invalid(format!(
+                "there is more than one tax rate for category: {}",
+                rate.category
+            ));
+        }
+        categories.push(category);
+    }
+    Ok(())
+}
+
+// Price the snapshotted lines of a cart in the currency of its items with the current rules
+fn price_lines(
+    items: &[Item],
+    lines: &[OrderLine],
+    delivery_distance_km: Option<f64>,
+    tip: Option<Money>,
+    promotion: Option<&Promotion>,
+) -> Result<PriceBreakdown, Error> {
+    let rules = PRICING_RULES.with(|r| r.borrow().get().clone());
+    let currency = items
+        .first()
+        .map_or_else(base_currency, |item| item.price.currency);
+    let overflow = || Error::Overflow {
+        msg: "order total overflows".to_string(),
+    };
+
+    // Add up the line totals, and the totals of the lines the promotion covers
+    let covered =
+        |item: &Item| promotion.is_some_and(|promotion| promotion.covers_category(&item.category));
+    let mut subtotal = Money::zero(currency);
+    let mut discounted = Money::zero(currency);
+    let mut discounted_lines = 0;
+    for (item, line) in items.iter().zip(lines) {
+        subtotal = subtotal.checked_add(line.line_total).ok_or_else(overflow)?;
+        if covered(item) {
+            discounted = discounted
+                .checked_add(line.line_total)
+                .ok_or_else(overflow)?;
+            discounted_lines += 1;
+        }
+    }
+
+    let tip = tip.unwrap_or(Money::zero(currency));
+    if tip.currency != currency {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "the tip must be in {:?}, the currency of the items",
+                currency
+            ),
+        });
+    }
+    let delivery_fee = rules.delivery_fee(currency, delivery_distance_km)?;
+    let small_order_fee = rules.small_order_fee(subtotal);
+    let service_fee = subtotal.bps_share(rules.service_fee_bps);
+    let discount = match promotion {
+        Some(promotion) => promotion_discount(
+            promotion,
+            subtotal,
+            discounted,
+            discounted_lines,
+            delivery_fee,
+        )?,
+        None => Money::zero(currency),
+    };
+
+    // Tax each line at the rate of its category, after its share of a discount on the lines.
+    // The discount is shared in proportion to the line totals, rounding the running total so
+    // the shares add up to the discount and none exceeds its line
+    let delivery_discount = match promotion.map(|promotion| promotion.discount) {
+        Some(Discount::FreeDelivery) => discount,
+        _ => Money::zero(currency),
+    };
+    let line_discount = (discount.amount_minor - delivery_discount.amount_minor) as u128;
+    let shared_until = |covered_total: u128| match discounted.amount_minor {
+        0 => 0,
+        discounted_total => (line_discount * covered_total / discounted_total as u128) as u64,
+    };
+    let mut covered_total: u128 = 0;
+    let mut tax = Money::zero(currency);
+    for (item, line) in items.iter().zip(lines) {
+        let mut taxed = line.line_total;
+        if covered(item) {
+            let shared_before = shared_until(covered_total);
+            covered_total += line.line_total.amount_minor as u128;
+            let share = shared_until(covered_total) - shared_before;
+            taxed = Money::new(taxed.amount_minor - share, currency);
+        }
+        let line_tax = taxed.bps_share(rules.tax_bps(&item.category));
+        tax = tax.checked_add(line_tax).ok_or_else(overflow)?;
+    }
+
+    let total = [delivery_fee, small_order_fee, service_fee, tax, tip]
+        .into_iter()
+        .try_fold(subtotal, Money::checked_add)
+        .and_then(|total| total.checked_sub(discount))
+        .ok_or_else(overflow)?;
+
+    Ok(PriceBreakdown {
+        subtotal,
+        delivery_fee,
+        delivery_distance_km,
+        small_order_fee,
+        service_fee,
+        tax,
+        tip,
+        discount,
+        delivery_discount: Some(delivery_discount),
+        total,
+    })
+}
+
+// Amount a promotion takes off an order, given the total of the lines it covers
+fn promotion_discount(
+    promotion: &Promotion,
+    subtotal: Money,
+    discounted: Money,
+    discounted_lines: usize,
+    delivery_fee: Money,
+) -> Result<Money, Error> {
+    let rejected = |msg: String| Error::InvalidPromotion { msg };
+    if discounted_lines == 0 {
+        return Err(rejected(format!(
+            "promo code: {} does not cover any item of the order",
+            promotion.code
+        )));
+    }
+    if let Some(min_subtotal) = promotion.min_subtotal {
+        if min_subtotal.currency != subtotal.currency
+            || subtotal.amount_minor < min_subtotal.amount_minor
+        {
+            return Err(rejected(format!(
+                "promo code: {} needs a subtotal of at least {} {:?}",
+                promotion.code, min_subtotal.amount_minor, min_subtotal.currency
+            )));
+        }
+    }
+    match promotion.discount {
+        Discount::Percentage { bps } => Ok(discounted.bps_share(bps)),
+        Discount::FixedAmount { amount } if amount.currency == discounted.currency => {
+            Ok(Money::new(
+                amount.amount_minor.min(discounted.amount_minor),
+                amount.currency,
+            ))
+        }
+        Discount::FixedAmount { amount } => Err(rejected(format!(
+            "promo code: {} only applies to orders in {:?}",
+            promotion.code, amount.currency
+        ))),
+        Discount::FreeDelivery => Ok(delivery_fee),
+    }
+}
+
+// Currency of the canister, which is US dollars if none was chosen at install
+fn base_currency() -> Currency {
+    CONFIG
+        .with(|c| c.borrow().get().base_currency)
+        .unwrap_or_default()
+}
+
+// Reject an amount that is not in the base currency, which every stored price, fee and
+// discount uses
+fn require_base_currency(amount: &Money, field: &str) -> Result<(), Error> {
+    if amount.currency != base_currency() {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "{} must be in {:?}, the base currency",
+                field,
+                base_currency()
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Define a struct for the cursor of a paginated list query
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct Page {
+    // Id of the last entry of the previous page, or none for the first page
+    start_after: Option<u64>,
+    limit: u32,
+}
+
+// Page size for a requested limit, using the default for 0 and capping it at the maximum
+fn page_limit(limit: u32) -> usize {
+    let limit = match limit {
+        0 => DEFAULT_PAGE_LIMIT,
+        limit => limit.min(MAX_PAGE_LIMIT),
+    };
+    limit as usize
+}
+
+impl Page {
+    fn limit(&self) -> usize {
+        page_limit(self.limit)
+    }
+
+    // Lower bound of a range over u64 keys for this page
+    fn lower_bound(&self) -> Bound<u64> {
+        match self.start_after {
+            Some(id) => Bound::Excluded(id),
+            None => Bound::Unbounded,
+        }
+    }
+}
+
+// Define a struct for one page of a paginated list query
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PageResult<T> {
+    items: Vec<T>,
+    // Pass as 'start_after' to fetch the next page; none once the last page is reached
+    next_cursor: Option<u64>,
+}
+
+// Define a struct for one page of a v2 list query, with the number of matches over all pages
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ListResult<T> {
+    items: Vec<T>,
+    next_cursor: Option<u64>,
+    // Exact where a count is maintained, otherwise at most 'MAX_LIST_TOTAL'
+    total: u64,
+}
+
+impl<T> PageResult<T> {
+    fn map<U>(self, f: impl FnMut(T) -> U) -> PageResult<U> {
+        PageResult {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+        }
+    }
+
+    fn with_total(self, total: u64) -> ListResult<T> {
+        ListResult {
+            items: self.items,
+            next_cursor: self.next_cursor,
+            total,
+        }
+    }
+}
+
+// Count the matches of a list query, stopping at 'MAX_LIST_TOTAL'
+fn capped_total<T>(matches: impl Iterator<Item = T>) -> u64 {
+    matches.take(MAX_LIST_TOTAL).count() as u64
+}
+
+// Take one page of (id, entry) pairs from an iterator ordered by id
+fn collect_page<T>(entries: impl Iterator<Item = (u64, T)>, page: &Page) -> PageResult<T> {
+    let limit = page.limit();
+    let mut items = Vec::with_capacity(limit);
+    let mut last_id = None;
+    let mut has_more = false;
+    for (id, entry) in entries {
+        if items.len() == limit {
+            has_more = true;
+            break;
+        }
+        last_id = Some(id);
+        items.push(entry);
+    }
+
+    PageResult {
+        items,
+        next_cursor: if has_more { last_id } else { None },
+    }
+}
+
+// Range over the entries stored under one leading key of a (key, id) index, starting after
+// the cursor of a page
+fn index_range<'a, K>(
+    index: &'a StableBTreeMap<(K, u64), (), Memory>,
+    key: K,
+    start_after: Option<u64>,
+) -> impl Iterator<Item = u64> + 'a
+where
+    K: Ord + Clone + 'a,
+    (K, u64): BoundedStorable,
+{
+    let lower = match start_after {
+        Some(id) => Bound::Excluded((key.clone(), id)),
+        None => Bound::Included((key.clone(), 0)),
+    };
+    index
+        .range((lower, Bound::Included((key, u64::MAX))))
+        .map(|((_, id), _)| id)
+}
+
+// Read one page of the entities listed under one key of a (key, id) index; 'get' may
+// skip ids by returning none
+fn index_page<K, V>(
+    index: &StableBTreeMap<(K, u64), (), Memory>,
+    key: K,
+    page: &Page,
+    get: impl Fn(u64) -> Option<V>,
+) -> PageResult<V>
+where
+    K: Ord + Clone,
+    (K, u64): BoundedStorable,
+{
+    let entries =
+        index_range(index, key, page.start_after).filter_map(|id| get(id).map(|v| (id, v)));
+    collect_page(entries, page)
+}
+
+// Read one page of a table keyed by id, touching only the requested slice
+fn page_of<V: BoundedStorable + Clone>(
+    map: &StableBTreeMap<u64, V, Memory>,
+    page: &Page,
+) -> PageResult<V> {
+    collect_page(map.range((page.lower_bound(), Bound::Unbounded)), page)
+}
+
+// Define a struct for a menu search; every filter that is set must match
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SearchQuery {
+    // Words that must all appear in the name or description, ignoring case
+    text: Option<String>,
+    category: Option<String>,
+    restaurant_id: Option<u64>,
+    // Items priced in another currency than a price bound do not match it
+    min_price: Option<Money>,
+    max_price: Option<Money>,
+    // Items without reviews never match a minimum rating
+    min_rating: Option<f64>,
+    // Tags that must all be present on the item
+    dietary_tags: Vec<String>,
+    // Only items of restaurants that are accepting orders
+    available_only: bool,
+    // Item id order when not set
+    sort_by: Option<SearchSort>,
+    // The cursor is the id of the last item of the previous page, in the chosen order
+    page: Page,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum SearchSort {
+    PriceAscending,
+    PriceDescending,
+    // Highest Bayesian average rating first
+    Rating,
+    // Most ordered first
+    Popularity,
+}
+
+// Define a struct for one search result with the figures it can be sorted by
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SearchHit {
+    item: Item,
+    average_rating: Option<f64>,
+    // Units of the item ordered so far
+    order_count: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SearchResult {
+    hits: Vec<SearchHit>,
+    next_cursor: Option<u64>,
+    // Number of matches over all pages
+    total: u64,
+}
+
+// Define an enum for the problems that prevent an 'Order' from being placed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum OrderProblem {
+    EmptyCart,
+    MissingClient {
+        client_id: u64,
+    },
+    UnknownItem {
+        line: u64,
+        item_id: u64,
+    },
+    ZeroQuantity {
+        line: u64,
+        item_id: u64,
+    },
+    TooManyLines {
+        lines: u64,
+        max: u64,
+    },
+    QuantityAboveLimit {
+        line: u64,
+        item_id: u64,
+        quantity: u64,
+        max: u64,
+    },
+    // The item is archived, sold out or hidden
+    ItemUnavailable {
+        line: u64,
+        item_id: u64,
+    },
+    // The item is priced in another currency than the earlier lines of the order
+    MixedCurrencies {
+        line: u64,
+        item_id: u64,
+        currency: Currency,
+    },
+}
+
+// Define a wrapper so a 'Principal' can be used as a stable map key
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct StorablePrincipal(Principal);
+
+// Implement the 'Storable' trait for the 'Client', 'Order', 'Review' and 'Item' structs
+impl Storable for Client {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for LedgerEntry {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Refund {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Order {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for StoredOrderBytes {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StoredOrderBytes(bytes.into_owned())
+    }
+}
+
+impl<V> Storable for RawBytes<V> {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RawBytes(bytes.into_owned(), PhantomData)
+    }
+}
+
+impl Storable for Promotion {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Review {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Item {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Restaurant {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Courier {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for LocationPoint {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for OrderEta {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for OrderEvent {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for RoleSet {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Config {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for PricingRules {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for StorablePrincipal {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StorablePrincipal(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+// Implement the 'BoundedStorable' trait for the 'Client', 'Order', 'Review' and 'Item' structs
+impl BoundedStorable for Client {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for LedgerEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Refund {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Order {
+    const MAX_SIZE: u32 = 16 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for StoredOrderBytes {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Raw values are bounded like the values they hold, so they can open the same storage
+impl<V: BoundedStorable> BoundedStorable for RawBytes<V> {
+    const MAX_SIZE: u32 = V::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = V::IS_FIXED_SIZE;
+}
+
+impl BoundedStorable for Promotion {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Review {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Item {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Restaurant {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Courier {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for LocationPoint {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for OrderEta {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for OrderEvent {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for RoleSet {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for StorablePrincipal {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local static variables for memory management and storage
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CLIENT_STORAGE: RefCell<StableBTreeMap<u64, Client, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    // Orders stored before line item snapshots, moved into 'ORDER_STORAGE' on upgrade
+    static LEGACY_ORDER_STORAGE: RefCell<StableBTreeMap<u64, StoredOrderBytes, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+
+    static ORDER_STORAGE: RefCell<StableBTreeMap<u64, Order, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    // Keyed by (client id, order id) so a client's orders are a contiguous range
+    static CLIENT_ORDER_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // Keyed by (courier id, order id) for the orders currently assigned to a courier
+    static COURIER_ORDER_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    // Keyed by (completed at, order id) for the completed orders whose routes await pruning
+    static ROUTE_PRUNE_QUEUE: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    // Secondary indexes keyed by (owner key, entity id) so lookups are range scans
+    static CATEGORY_ITEM_INDEX: RefCell<StableBTreeMap<(CategoryKey, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static RESTAURANT_ITEM_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    static ITEM_REVIEW_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // Keyed by (status discriminant, order id)
+    static ORDER_STATUS_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    // Inverted index from the words of an item's name and description to the item
+    static SEARCH_INDEX: RefCell<StableBTreeMap<(TokenKey, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // Units ordered per item, used to rank search results by popularity
+    static ITEM_ORDER_COUNTS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    // Keyed by (order id, item id) so an item can be reviewed once per order
+    static ORDER_ITEM_REVIEW_INDEX: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    static CLIENT_REVIEW_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    // Keyed by order id; an order is refunded at most once
+    static REFUND_STORAGE: RefCell<StableBTreeMap<u64, Refund, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    // Keyed by (order id, sequence number within the order)
+    static LEDGER_ENTRIES: RefCell<StableBTreeMap<(u64, u64), LedgerEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    static PROMOTION_STORAGE: RefCell<StableBTreeMap<u64, Promotion, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // Keyed by normalised promo code, holding the promotion id
+    static PROMOTION_CODE_INDEX: RefCell<StableBTreeMap<PromoCodeKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // Keyed by (promotion id, client id), holding the number of orders the client placed with it
+    static PROMOTION_REDEMPTIONS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    // Orders with a ledger call in progress, so they are not paid or refunded twice
+    static LEDGER_CALLS_IN_FLIGHT: RefCell<HashSet<u64>> = RefCell::new(HashSet::new());
+
+    static REVIEW_STORAGE: RefCell<StableBTreeMap<u64, Review, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static ITEM_STORAGE: RefCell<StableBTreeMap<u64, Item, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    // Keyed by (order id, sequence number) so an order's timeline is a contiguous range
+    static ORDER_EVENTS: RefCell<StableBTreeMap<(u64, u64), OrderEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    static PRINCIPAL_INDEX: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    static ROLE_STORAGE: RefCell<StableBTreeMap<StorablePrincipal, RoleSet, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    static RESTAURANT_STORAGE: RefCell<StableBTreeMap<u64, Restaurant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static COURIER_STORAGE: RefCell<StableBTreeMap<u64, Courier, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static COURIER_INDEX: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    // Keyed by (order id, sequence number) so an order's route is a contiguous range
+    static ORDER_LOCATIONS: RefCell<StableBTreeMap<(u64, u64), LocationPoint, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    static ORDER_ETAS: RefCell<StableBTreeMap<u64, OrderEta, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    static CONFIG: RefCell<Cell<Config, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))), Config::default())
+            .expect("Cannot create the config")
+    );
+
+    static PRICING_RULES: RefCell<Cell<PricingRules, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+            PricingRules::default(),
+        )
+        .expect("Cannot create the pricing rules")
+    );
+}
+
+// Retrieve the roles granted to a principal
+fn roles_of(principal: Principal) -> Vec<Role> {
+    ROLE_STORAGE
+        .with(|s| s.borrow().get(&StorablePrincipal(principal)))
+        .map_or_else(Vec::new, |set| set.roles)
+}
+
+// Check if a principal holds a role
+fn has_role(principal: Principal, role: Role) -> bool {
+    roles_of(principal).contains(&role)
+}
+
+// Add a role to the set granted to a principal
+fn add_role(principal: Principal, role: Role) {
+    let mut roles = roles_of(principal);
+    if !roles.contains(&role) {
+        roles.push(role);
+        ROLE_STORAGE.with(|s| {
+            s.borrow_mut()
+                .insert(StorablePrincipal(principal), RoleSet { roles })
+        });
+    }
+}
+
+// Remove a role from a principal, dropping the entry once no roles are left
+fn remove_role(principal: Principal, role: Role) {
+    let mut roles = roles_of(principal);
+    roles.retain(|r| *r != role);
+    ROLE_STORAGE.with(|s| {
+        let mut s = s.borrow_mut();
+        if roles.is_empty() {
+            s.remove(&StorablePrincipal(principal));
+        } else {
+            s.insert(StorablePrincipal(principal), RoleSet { roles });
+        }
+    });
+}
+
+// Check that the caller holds one of the allowed roles; admins are always allowed
+fn require_role(allowed: &[Role]) -> Result<(), Error> {
+    let caller = env::caller();
+    let roles = roles_of(caller);
+    if roles.contains(&Role::Admin) || allowed.iter().any(|role| roles.contains(role)) {
+        Ok(())
+    } else {
+        Err(Error::Forbidden {
+            msg: format!(
+                "principal: {} requires one of the roles: {:?}",
+                caller, allowed
+            ),
+        })
+    }
+}
+
+// Check that the caller owns the restaurant; admins are always allowed
+fn require_restaurant_owner(restaurant: &Restaurant) -> Result<(), Error> {
+    let caller = env::caller();
+    if restaurant.owner == caller || has_role(caller, Role::Admin) {
+        Ok(())
+    } else {
+        Err(Error::Forbidden {
+            msg: format!(
+                "principal: {} does not own restaurant id: {}",
+                caller, restaurant.id
+            ),
+        })
+    }
+}
+
+// Check that the caller owns the restaurant with the given id; anything without a restaurant,
+// or whose restaurant no longer exists, is left to admins
+fn require_owner_of_restaurant(restaurant_id: Option<u64>) -> Result<(), Error> {
+    match restaurant_id.and_then(|id| RESTAURANT_STORAGE.with(|s| s.borrow().get(&id))) {
+        Some(restaurant) => require_restaurant_owner(&restaurant),
+        None => require_role(&[Role::Admin]),
+    }
+}
+
+// Check that the caller is the given client or holds one of the allowed roles
+fn require_owner_or_role(client_id: u64, allowed: &[Role]) -> Result<(), Error> {
+    if caller_client_id().ok() == Some(client_id) {
+        return Ok(());
+    }
+    require_role(allowed)
+}
+
+// Resolve the id of the client bound to the caller
+fn caller_client_id() -> Result<u64, Error> {
+    let caller = env::caller();
+    PRINCIPAL_INDEX
+        .with(|s| s.borrow().get(&StorablePrincipal(caller)))
+        .ok_or(Error::Unauthorized {
+            msg: format!("no client is registered for principal: {}", caller),
+        })
+}
+
+// Resolve the id of the courier bound to the caller
+fn caller_courier_id() -> Result<u64, Error> {
+    let caller = env::caller();
+    COURIER_INDEX
+        .with(|s| s.borrow().get(&StorablePrincipal(caller)))
+        .ok_or(Error::Unauthorized {
+            msg: format!("no courier is registered for principal: {}", caller),
+        })
+}
+
+// Check that the caller is the courier who accepted the order
+fn require_assigned_courier(order: &Order) -> Result<(), Error> {
+    let courier_id = caller_courier_id()?;
+    match &order.assignment {
+        Some(assignment) if assignment.courier_id == courier_id && assignment.accepted => Ok(()),
+        _ => Err(Error::Forbidden {
+            msg: format!(
+                "order id: {} is not assigned to courier id: {}",
+                order.id, courier_id
+            ),
+        }),
+    }
+}
+
+// Check that the caller is the order's client, its assigned courier or an admin
+fn require_order_participant(order: &Order) -> Result<(), Error> {
+    let is_courier = order
+        .assignment
+        .as_ref()
+        .is_some_and(|a| caller_courier_id().ok() == Some(a.courier_id));
+    if is_courier {
+        return Ok(());
+    }
+    require_owner_or_role(order.client_id, &[Role::Admin])
+}
+
+// Check that the caller takes part in the order or owns the restaurant it was placed with
+fn require_order_party(order: &Order) -> Result<(), Error> {
+    if require_owner_of_restaurant(order.restaurant_id).is_ok() {
+        return Ok(());
+    }
+    require_order_participant(order)
+}
+
+// Queue the route of a completed order for pruning once its retention has passed
+fn queue_route_pruning(order_id: u64, completed_at: u64) {
+    let has_route = ORDER_LOCATIONS.with(|s| {
+        s.borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .next()
+            .is_some()
+    });
+    if has_route {
+        ROUTE_PRUNE_QUEUE.with(|s| s.borrow_mut().insert((completed_at, order_id), ()));
+    }
+}
+
+// Find when an order reached a final state, according to its timeline
+fn order_completed_at(order_id: u64) -> Option<u64> {
+    ORDER_EVENTS.with(|s| {
+        s.borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .filter(|(_, event)| event.status.is_final())
+            .map(|(_, event)| event.timestamp)
+            .last()
+    })
+}
+
+// Record that the amount captured for a cancelled order is owed back to its client
+fn create_refund(order: &Order, reason: CancellationReason) {
+    if let Some(amount) = order.amount_captured.filter(|amount| !amount.is_zero()) {
+        REFUND_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                order.id,
+                Refund {
+                    order_id: order.id,
+                    client_id: order.client_id,
+                    amount,
+                    reason,
+                    created_at: env::time(),
+                    settled_at: None,
+                },
+            )
+        });
+    }
+}
+
+// Find the promotion of a promo code and check that the client may redeem it now
+fn redeemable_promotion(
+    code: &str,
+    client_id: u64,
+    restaurant_id: Option<u64>,
+) -> Result<Promotion, Error> {
+    let rejected = |msg: String| Err(Error::InvalidPromotion { msg });
+    let promotion = match PROMOTION_CODE_INDEX
+        .with(|s| s.borrow().get(&promo_code_key(code)))
+        .and_then(|id| PROMOTION_STORAGE.with(|s| s.borrow().get(&id)))
+    {
+        Some(promotion) => promotion,
+        None => return rejected(format!("promo code: {} does not exist", code.trim())),
+    };
+
+    let now = env::time();
+    if now < promotion.valid_from {
+        return rejected(format!("promo code: {} is not valid yet", promotion.code));
+    }
+    if promotion.valid_until.is_some_and(|until| now >= until) {
+        return rejected(format!("promo code: {} has expired", promotion.code));
+    }
+    if !promotion.restaurant_ids.is_empty()
+        && !restaurant_id.is_some_and(|id| promotion.restaurant_ids.contains(&id))
+    {
+        return rejected(format!(
+            "promo code: {} cannot be used at this restaurant",
+            promotion.code
+        ));
+    }
+    if promotion
+        .max_redemptions
+        .is_some_and(|max| promotion.redemptions >= max)
+    {
+        return rejected(format!("promo code: {} has been used up", promotion.code));
+    }
+    let client_redemptions = PROMOTION_REDEMPTIONS
+        .with(|s| s.borrow().get(&(promotion.id, client_id)))
+        .unwrap_or(0);
+    if promotion
+        .max_redemptions_per_client
+        .is_some_and(|max| client_redemptions >= max)
+    {
+        return rejected(format!(
+            "promo code: {} has already been used by client id: {}",
+            promotion.code, client_id
+        ));
+    }
+    if promotion.first_order_only && has_placed_order(client_id) {
+        return rejected(format!(
+            "promo code: {} is only valid for a first order",
+            promotion.code
+        ));
+    }
+    Ok(promotion)
+}
+
+// Whether a client has placed an order that was not cancelled
+fn has_placed_order(client_id: u64) -> bool {
+    CLIENT_ORDER_INDEX.with(|s| {
+        index_range(&s.borrow(), client_id, None).any(|order_id| {
+            ORDER_STORAGE
+                .with(|s| s.borrow().get(&order_id))
+                .is_some_and(|order| {
+                    !matches!(order.status, OrderStatus::Cancelled | OrderStatus::Refunded)
+                })
+        })
+    })
+}
+
+// Count an order placed with a promotion, or release it again when the order is cancelled
+fn update_redemptions(promotion_id: u64, client_id: u64, add: bool) {
+    let update = |count: u64| {
+        if add {
+            count.saturating_add(1)
+        } else {
+            count.saturating_sub(1)
+        }
+    };
+    if let Some(mut promotion) = PROMOTION_STORAGE.with(|s| s.borrow().get(&promotion_id)) {
+        promotion.redemptions = update(promotion.redemptions);
+        store_promotion(&promotion);
+    }
+    PROMOTION_REDEMPTIONS.with(|s| {
+        let mut redemptions = s.borrow_mut();
+        let key = (promotion_id, client_id);
+        match update(redemptions.get(&key).unwrap_or(0)) {
+            0 => redemptions.remove(&key),
+            count => redemptions.insert(key, count),
+        };
+    });
+}
+
+// Replace a principal by the anonymous principal in the timeline and ledger of an order, and
+// drop the notes it left on the timeline; the ledger keeps the block index of each transfer
+fn forget_principal_on_order(order_id: u64, principal: Principal) {
+    ORDER_EVENTS.with(|s| {
+        let mut events = s.borrow_mut();
+        let recorded: Vec<((u64, u64), OrderEvent)> = events
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .filter(|(_, event)| event.actor == principal)
+            .collect();
+        for (key, mut event) in recorded {
+            event.actor = Principal::anonymous();
+            event.note = None;
+            events.insert(key, event);
+        }
+    });
+    LEDGER_ENTRIES.with(|s| {
+        let mut entries = s.borrow_mut();
+        let recorded: Vec<((u64, u64), LedgerEntry)> = entries
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .filter(|(_, entry)| entry.counterparty == principal)
+            .collect();
+        for (key, mut entry) in recorded {
+            entry.counterparty = Principal::anonymous();
+            entries.insert(key, entry);
+        }
+    });
+}
+
+// Append an entry to the internal payment ledger of an order
+fn record_ledger_entry(entry: LedgerEntry) {
+    LEDGER_ENTRIES.with(|s| {
+        let mut entries = s.borrow_mut();
+        let seq = entries
+            .range((entry.order_id, 0)..=(entry.order_id, u64::MAX))
+            .last()
+            .map_or(0, |((_, seq), _)| seq + 1);
+        entries.insert((entry.order_id, seq), entry);
+    });
+}
+
+// Record the payouts owed to the restaurant and courier of a delivered, paid order. The
+// courier is owed the tip and a share of the delivery fee, the restaurant the subtotal less
+// any discount, and the canister keeps the rest, including the service fee and the tax
+fn record_payouts(order: &Order) {
+    let amount = match order.amount_captured {
+        Some(amount) if !amount.is_zero() => amount,
+        _ => return,
+    };
+    let now = env::time();
+    let bps = CONFIG
+        .with(|c| c.borrow().get().courier_payout_bps)
+        .unwrap_or(DEFAULT_COURIER_PAYOUT_BPS);
+
+    // Orders placed before prices were broken down were charged their subtotal alone, so their
+    // courier is owed a share of the whole payment. Otherwise the courier shares the delivery
+    // fee actually charged, and the restaurant bears only the discount on its lines
+    let (courier_share, restaurant_share) = match &order.price_breakdown {
+        Some(breakdown) => {
+            let delivery_discount = breakdown.delivery_discount(order.promotion_id);
+            let charged_delivery_fee = breakdown
+                .delivery_fee
+                .checked_sub(delivery_discount)
+                .unwrap_or(Money::zero(breakdown.delivery_fee.currency));
+            let line_discount = breakdown
+                .discount
+                .amount_minor
+                .saturating_sub(delivery_discount.amount_minor);
+            (
+                breakdown
+                    .tip
+                    .checked_add(charged_delivery_fee.bps_share(bps))
+                    .expect("the tip and the delivery fee share the order currency"),
+                Money::new(
+                    breakdown
+                        .subtotal
+                        .amount_minor
+                        .saturating_sub(line_discount),
+                    breakdown.subtotal.currency,
+                ),
+            )
+        }
+        None => {
+            let courier_share = amount.bps_share(bps);
+            (
+                courier_share,
+                amount
+                    .checked_sub(courier_share)
+                    .expect("the courier share is part of the amount"),
+            )
+        }
+    };
+
+    // The accepted courier is owed its share
+    if let Some(courier) = order
+        .assignment
+        .as_ref()
+        .filter(|assignment| assignment.accepted)
+        .and_then(|assignment| COURIER_STORAGE.with(|s| s.borrow().get(&assignment.courier_id)))
+    {
+        if !courier_share.is_zero() {
+            record_ledger_entry(LedgerEntry {
+                order_id: order.id,
+                kind: LedgerEntryKind::CourierPayout,
+                counterparty: courier.principal,
+                amount: courier_share,
+                block_index: None,
+                created_at: now,
+            });
+        }
+    }
+
+    // The restaurant owner is owed the rest of the food
+    if let Some(restaurant) = order
+        .restaurant_id
+        .and_then(|restaurant_id| RESTAURANT_STORAGE.with(|s| s.borrow().get(&restaurant_id)))
+    {
+        if !restaurant_share.is_zero() {
+            record_ledger_entry(LedgerEntry {
+                order_id: order.id,
+                kind: LedgerEntryKind::RestaurantPayout,
+                counterparty: restaurant.owner,
+                amount: restaurant_share,
+                block_index: None,
+                created_at: now,
+            });
+        }
+    }
+}
+
+// Resolve the configured payment ledger
+fn payment_ledger() -> Result<Principal, Error> {
+    CONFIG
+        .with(|c| c.borrow().get().ledger_canister_id)
+        .ok_or(Error::InvalidPayload {
+            msg: "no payment ledger is configured".to_string(),
+        })
+}
+
+// Mark an order as having a ledger call in progress, failing if it already has one
+fn begin_ledger_call(order_id: u64) -> Result<(), Error> {
+    if LEDGER_CALLS_IN_FLIGHT.with(|s| s.borrow_mut().insert(order_id)) {
+        Ok(())
+    } else {
+        Err(Error::PaymentFailed {
+            msg: format!(
+                "a ledger call for order id: {} is already in progress",
+                order_id
+            ),
+        })
+    }
+}
+
+fn end_ledger_call(order_id: u64) {
+    LEDGER_CALLS_IN_FLIGHT.with(|s| s.borrow_mut().remove(&order_id));
+}
+
+// Time an order was placed, from the first event of its timeline
+fn order_placed_at(order_id: u64) -> Option<u64> {
+    ORDER_EVENTS.with(|s| {
+        s.borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .next()
+            .map(|(_, event)| event.timestamp)
+    })
+}
+
+// Remove the location points of a batch of the orders completed before the retention window
+fn prune_order_locations() {
+    let cutoff = env::time().saturating_sub(LOCATION_RETENTION_NANOS);
+
+    // Take the oldest completed orders past their retention from the queue
+    let due: Vec<(u64, u64)> = ROUTE_PRUNE_QUEUE.with(|s| {
+        s.borrow()
+            .range(..=(cutoff, u64::MAX))
+            .take(LOCATION_PRUNE_BATCH)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    for (completed_at, order_id) in due {
+        ROUTE_PRUNE_QUEUE.with(|s| s.borrow_mut().remove(&(completed_at, order_id)));
+        ORDER_LOCATIONS.with(|s| {
+            let stale: Vec<(u64, u64)> = s
+                .borrow()
+                .range((order_id, 0)..=(order_id, u64::MAX))
+                .filter(|(_, point)| point.timestamp <= completed_at)
+                .map(|(key, _)| key)
+                .collect();
+            for key in stale {
+                s.borrow_mut().remove(&key);
+            }
+        });
+    }
+}
+
+// Convert a distance at a given speed into nanoseconds of travel
+fn travel_nanos(distance_km: f64, speed_kmh: f64) -> u64 {
+    (distance_km / speed_kmh * 60.0 * NANOS_PER_MINUTE as f64) as u64
+}
+
+// Estimate when an in-flight order will arrive at its delivery location
+fn estimate_arrival(order: &Order) -> Option<OrderEta> {
+    if order.status.is_final() {
+        return None;
+    }
+    let destination = order.delivery_location?;
+    let now = env::time();
+
+    // Use the assigned courier's speed and most recent position, if known
+    let courier = order
+        .assignment
+        .as_ref()
+        .and_then(|a| COURIER_STORAGE.with(|s| s.borrow().get(&a.courier_id)));
+    let speed = courier
+        .as_ref()
+        .map_or(DEFAULT_SPEED_KMH, |c| c.vehicle.average_speed_kmh());
+    let courier_location = ORDER_LOCATIONS
+        .with(|s| {
+            s.borrow()
+                .range((order.id, 0)..=(order.id, u64::MAX))
+                .last()
+                .map(|(_, point)| Location {
+                    latitude: point.latitude,
+                    longitude: point.longitude,
+                })
+        })
+        .or_else(|| courier.and_then(|c| c.location));
+    let restaurant_location = order
+        .restaurant_id
+        .and_then(|id| RESTAURANT_STORAGE.with(|s| s.borrow().get(&id)))
+        .map(|r| Location {
+            latitude: r.latitude,
+            longitude: r.longitude,
+        });
+
+    let (departure, pickup_km, delivery_km) = match order.status {
+        // The food is on its way, so only the trip to the customer is left
+        OrderStatus::PickedUp | OrderStatus::InTransit => {
+            let origin = courier_location.or(restaurant_location)?;
+            (now, 0.0, origin.distance_km(&destination))
+        }
+        // The courier leaves once the food is ready and they have reached the restaurant
+        _ => {
+            let restaurant_location = restaurant_location?;
+            let ready_at = match order.status {
+                OrderStatus::ReadyForPickup => now,
+                _ => {
+                    let prep = order_prep_minutes(order) as u64 * NANOS_PER_MINUTE;
+                    let started_at = preparation_started_at(order.id).unwrap_or(now);
+                    (started_at + prep).max(now)
+                }
+            };
+            let pickup_km = courier_location.map_or(0.0, |l| l.distance_km(&restaurant_location));
+            let at_restaurant = now + travel_nanos(pickup_km, speed);
+            (
+                ready_at.max(at_restaurant),
+                pickup_km,
+                restaurant_location.distance_km(&destination),
+            )
+        }
+    };
+
+    Some(OrderEta {
+        order_id: order.id,
+        estimated_arrival: departure + travel_nanos(delivery_km, speed),
+        remaining_distance_km: pickup_km + delivery_km,
+        computed_at: now,
+    })
+}
+
+// Find the longest preparation time among the items of an order
+fn order_prep_minutes(order: &Order) -> u32 {
+    order
+        .lines
+        .iter()
+        .filter_map(|line| ITEM_STORAGE.with(|s| s.borrow().get(&line.item_id)))
+        .map(|item| item.prep_minutes.unwrap_or(DEFAULT_PREP_MINUTES))
+        .max()
+        .unwrap_or(DEFAULT_PREP_MINUTES)
+}
+
+// Find when the kitchen started preparing an order, according to its timeline
+fn preparation_started_at(order_id: u64) -> Option<u64> {
+    ORDER_EVENTS.with(|s| {
+        s.borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .find(|(_, event)| event.status == OrderStatus::Preparing)
+            .map(|(_, event)| event.timestamp)
+    })
+}
+
+// Recompute the stored ETA of an order, dropping it once the order is no longer in flight
+fn refresh_order_eta(order: &Order) {
+    ORDER_ETAS.with(|s| match estimate_arrival(order) {
+        Some(eta) => s.borrow_mut().insert(order.id, eta),
+        None => s.borrow_mut().remove(&order.id),
+    });
+}
+
+// Lowercase and trim a string and truncate it to fit an index key of 'N' bytes
+fn key_blob<const N: usize>(text: &str) -> Blob<N> {
+    let mut normalised = text.trim().to_lowercase();
+    let mut len = normalised.len().min(N);
+    while !normalised.is_char_boundary(len) {
+        len -= 1;
+    }
+    normalised.truncate(len);
+    Blob::try_from(normalised.as_bytes()).unwrap()
+}
+
+// Normalise a category so lookups ignore case and surrounding whitespace
+fn category_key(category: &str) -> CategoryKey {
+    key_blob(category)
+}
+
+// Normalise a promo code so lookups ignore case and surrounding whitespace
+fn promo_code_key(code: &str) -> PromoCodeKey {
+    key_blob(code)
+}
+
+// Store a promotion, keeping the promo code index in sync
+fn store_promotion(promotion: &Promotion) {
+    let previous =
+        PROMOTION_STORAGE.with(|s| s.borrow_mut().insert(promotion.id, promotion.clone()));
+    PROMOTION_CODE_INDEX.with(|s| {
+        let mut index = s.borrow_mut();
+        if let Some(previous) = previous {
+            index.remove(&promo_code_key(&previous.code));
+        }
+        index.insert(promo_code_key(&promotion.code), promotion.id);
+    });
+}
+
+// Split text into the distinct search tokens it contains
+fn search_tokens(text: &str) -> Vec<TokenKey> {
+    let mut tokens: Vec<TokenKey> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(key_blob)
+        .collect();
+    tokens.sort();
+    tokens.dedup();
+    tokens
+}
+
+fn item_tokens(item: &Item) -> Vec<TokenKey> {
+    search_tokens(&format!("{} {}", item.name, item.description))
+}
+
+// Collect the entity ids stored under one leading key of a (key, id) index
+fn index_ids<K>(index: &StableBTreeMap<(K, u64), (), Memory>, key: K) -> Vec<u64>
+where
+    K: Ord + Clone,
+    (K, u64): BoundedStorable,
+{
+    index_range(index, key, None).collect()
+}
+
+// Store an item, keeping the category and restaurant indexes in sync
+fn store_item(item: &Item) {
+    if let Some(previous) = ITEM_STORAGE.with(|s| s.borrow_mut().insert(item.id, item.clone())) {
+        unindex_item(&previous);
+    }
+    CATEGORY_ITEM_INDEX.with(|s| {
+        s.borrow_mut()
+            .insert((category_key(&item.category), item.id), ())
+    });
+    if let Some(restaurant_id) = item.restaurant_id {
+        RESTAURANT_ITEM_INDEX.with(|s| s.borrow_mut().insert((restaurant_id, item.id), ()));
+    }
+    SEARCH_INDEX.with(|s| {
+        let mut index = s.borrow_mut();
+        for token in item_tokens(item) {
+            index.insert((token, item.id), ());
+        }
+    });
+}
+
+// Remove an item together with its index entries
+fn remove_item(id: u64) -> Option<Item> {
+    let removed = ITEM_STORAGE.with(|s| s.borrow_mut().remove(&id));
+    if let Some(item) = &removed {
+        unindex_item(item);
+    }
+    removed
+}
+
+// Remove the category, restaurant and search index entries of an item
+fn unindex_item(item: &Item) {
+    CATEGORY_ITEM_INDEX.with(|s| {
+        s.borrow_mut()
+            .remove(&(category_key(&item.category), item.id))
+    });
+    if let Some(restaurant_id) = item.restaurant_id {
+        RESTAURANT_ITEM_INDEX.with(|s| s.borrow_mut().remove(&(restaurant_id, item.id)));
+    }
+    SEARCH_INDEX.with(|s| {
+        let mut index = s.borrow_mut();
+        for token in item_tokens(item) {
+            index.remove(&(token, item.id));
+        }
+    });
+}
+
+// Add ordered units to the popularity counters of the items of an order
+fn count_ordered_items(order: &Order) {
+    ITEM_ORDER_COUNTS.with(|s| {
+        let mut counts = s.borrow_mut();
+        for line in &order.lines {
+            let count = counts.get(&line.item_id).unwrap_or(0);
+            counts.insert(line.item_id, count.saturating_add(line.quantity));
+        }
+    });
+}
+
+// Retrieve an item if it is shown on menus
+fn listed_item(id: u64) -> Option<Item> {
+    ITEM_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .filter(Item::is_listed)
+}
+
+// Retrieve an item if it is shown on menus and, if a restaurant is given, sold by it
+fn listed_item_of(restaurant_id: Option<u64>) -> impl Fn(u64) -> Option<Item> {
+    move |id| {
+        listed_item(id)
+            .filter(|item| restaurant_id.is_none() || item.restaurant_id == restaurant_id)
+    }
+}
+
+// Add a rating to, or remove it from, the totals of its item and the item's restaurant
+fn update_rating_stats(review: &Review, add: bool) {
+    let apply = |stats: Option<RatingStats>| {
+        let mut stats = stats.unwrap_or_default();
+        if add {
+            stats.add(review.rating);
+        } else {
+            stats.remove(review.rating);
+        }
+        Some(stats)
+    };
+
+    let item = match ITEM_STORAGE.with(|s| s.borrow().get(&review.item_id)) {
+        Some(item) => item,
+        None => return,
+    };
+    if let Some(mut restaurant) = item
+        .restaurant_id
+        .and_then(|restaurant_id| RESTAURANT_STORAGE.with(|s| s.borrow().get(&restaurant_id)))
+    {
+        restaurant.rating = apply(restaurant.rating);
+        RESTAURANT_STORAGE.with(|s| s.borrow_mut().insert(restaurant.id, restaurant));
+    }
+    // Only the totals change, so the item's index entries stay valid
+    let item = Item {
+        rating: apply(item.rating),
+        ..item
+    };
+    ITEM_STORAGE.with(|s| s.borrow_mut().insert(item.id, item));
+}
+
+// Store a review, keeping the item review index in sync
+fn store_review(review: &Review) {
+    if let Some(previous) =
+        REVIEW_STORAGE.with(|s| s.borrow_mut().insert(review.id, review.clone()))
+    {
+        update_rating_stats(&previous, false);
+    }
+    update_rating_stats(review, true);
+    ITEM_REVIEW_INDEX.with(|s| s.borrow_mut().insert((review.item_id, review.id), ()));
+    if let Some(client_id) = review.client_id {
+        CLIENT_REVIEW_INDEX.with(|s| s.borrow_mut().insert((client_id, review.id), ()));
+    }
+    if let Some(order_id) = review.order_id {
+        ORDER_ITEM_REVIEW_INDEX
+            .with(|s| s.borrow_mut().insert((order_id, review.item_id), review.id));
+    }
+}
+
+// Remove a review together with its index entry
+fn remove_review(id: u64) -> Option<Review> {
+    let removed = REVIEW_STORAGE.with(|s| s.borrow_mut().remove(&id));
+    if let Some(review) = &removed {
+        update_rating_stats(review, false);
+        ITEM_REVIEW_INDEX.with(|s| s.borrow_mut().remove(&(review.item_id, review.id)));
+        if let Some(client_id) = review.client_id {
+            CLIENT_REVIEW_INDEX.with(|s| s.borrow_mut().remove(&(client_id, review.id)));
+        }
+        if let Some(order_id) = review.order_id {
+            ORDER_ITEM_REVIEW_INDEX.with(|s| s.borrow_mut().remove(&(order_id, review.item_id)));
+        }
+    }
+    removed
+}
+
+// Store an order, keeping the status and courier indexes in sync
+fn store_order(order: &Order) {
+    if let Some(previous) = ORDER_STORAGE.with(|s| s.borrow_mut().insert(order.id, order.clone())) {
+        ORDER_STATUS_INDEX.with(|s| s.borrow_mut().remove(&(previous.status as u64, order.id)));
+        if let Some(assignment) = &previous.assignment {
+            COURIER_ORDER_INDEX.with(|s| s.borrow_mut().remove(&(assignment.courier_id, order.id)));
+        }
+    }
+    ORDER_STATUS_INDEX.with(|s| s.borrow_mut().insert((order.status as u64, order.id), ()));
+    if let Some(assignment) = &order.assignment {
+        COURIER_ORDER_INDEX.with(|s| s.borrow_mut().insert((assignment.courier_id, order.id), ()));
+    }
+}
+
+// Rewrite the values of the storage in 'memory_id' that are stored in the 'Earlier' layout into
+// the current layout of 'V', so that values are only ever read in the current layout
+fn rewrite_stored_layout<K, V, Earlier>(memory_id: MemoryId)
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable + candid::CandidType + serde::de::DeserializeOwned,
+    Earlier: candid::CandidType + serde::de::DeserializeOwned + Into<V>,
+{
+    // Opened before the storage itself is first used, on the same memory
+    let mut stored: StableBTreeMap<K, RawBytes<V>, Memory> =
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(memory_id)));
+    let rewritten: Vec<(K, V)> = stored
+        .iter()
+        .filter(|(_, raw)| Decode!(&raw.0, V).is_err())
+        .map(|(key, raw)| {
+            let value = Decode!(&raw.0, Earlier)
+                .expect("Cannot decode a stored value in an earlier layout")
+                .into();
+            (key, value)
+        })
+        .collect();
+    for (key, value) in rewritten {
+        stored.insert(key, RawBytes(value.to_bytes().into_owned(), PhantomData));
+    }
+}
+
+// Rewrite the prices, order amounts, refunds and ledger entries stored before amounts carried
+// a currency, reading their amounts in the base currency
+fn rewrite_plain_amounts() {
+    rewrite_stored_layout::<u64, Item, PlainPriceItem>(MemoryId::new(4));
+    rewrite_stored_layout::<u64, Order, PlainAmountOrder>(MemoryId::new(14));
+    rewrite_stored_layout::<u64, Refund, PlainAmountRefund>(MemoryId::new(24));
+    rewrite_stored_layout::<(u64, u64), LedgerEntry, PlainAmountLedgerEntry>(MemoryId::new(25));
+}
+
+// Fill the secondary indexes from the stored entities if they have never been built
+fn backfill_indexes() {
+    let items_unindexed = CATEGORY_ITEM_INDEX.with(|s| s.borrow().is_empty())
+        || SEARCH_INDEX.with(|s| s.borrow().is_empty());
+    if items_unindexed {
+        let items: Vec<Item> = ITEM_STORAGE.with(|s| s.borrow().iter().map(|(_, i)| i).collect());
+        items.iter().for_each(store_item);
+    }
+    // Count the reviews stored before rating totals existed; this runs before the review
+    // indexes are rebuilt, which re-stores reviews without changing the totals
+    let ratings_uncounted = ITEM_STORAGE
+        .with(|s| s.borrow().iter().all(|(_, i)| i.rating.is_none()))
+        && RESTAURANT_STORAGE.with(|s| s.borrow().iter().all(|(_, r)| r.rating.is_none()));
+    if ratings_uncounted {
+        let reviews: Vec<Review> =
+            REVIEW_STORAGE.with(|s| s.borrow().iter().map(|(_, r)| r).collect());
+        for review in &reviews {
+            update_rating_stats(review, true);
+        }
+    }
+    let reviews_unindexed = ITEM_REVIEW_INDEX.with(|s| s.borrow().is_empty())
+        || CLIENT_REVIEW_INDEX.with(|s| s.borrow().is_empty());
+    if reviews_unindexed {
+        let reviews: Vec<Review> =
+            REVIEW_STORAGE.with(|s| s.borrow().iter().map(|(_, r)| r).collect());
+        reviews.iter().for_each(store_review);
+    }
+    let orders_unindexed = ORDER_STATUS_INDEX.with(|s| s.borrow().is_empty())
+        || (COURIER_ORDER_INDEX.with(|s| s.borrow().is_empty())
+            && ORDER_STORAGE.with(|s| s.borrow().iter().any(|(_, o)| o.assignment.is_some())));
+    if orders_unindexed {
+        let orders: Vec<Order> =
+            ORDER_STORAGE.with(|s| s.borrow().iter().map(|(_, o)| o).collect());
+        orders.iter().for_each(store_order);
+    }
+    let popularity_uncounted = ITEM_ORDER_COUNTS.with(|s| s.borrow().is_empty());
+    if popularity_uncounted {
+        ORDER_STORAGE.with(|s| s.borrow().iter().for_each(|(_, o)| count_ordered_items(&o)));
+    }
+    // Queue the routes of the orders completed before the pruning queue existed
+    let routes_unqueued = ROUTE_PRUNE_QUEUE.with(|s| s.borrow().is_empty())
+        && ORDER_LOCATIONS.with(|s| !s.borrow().is_empty());
+    if routes_unqueued {
+        let mut order_ids: Vec<u64> = ORDER_LOCATIONS.with(|s| {
+            s.borrow()
+                .iter()
+                .map(|((order_id, _), _)| order_id)
+                .collect()
+        });
+        order_ids.dedup();
+        for order_id in order_ids {
+            if let Some(completed_at) = order_completed_at(order_id) {
+                queue_route_pruning(order_id, completed_at);
+            }
+        }
+    }
+    let client_orders_unindexed = CLIENT_ORDER_INDEX.with(|s| s.borrow().is_empty())
+        && ORDER_STORAGE.with(|s| !s.borrow().is_empty());
+    if client_orders_unindexed {
+        rebuild_client_orders();
+    }
+}
+
+// Rebuild the client order index and the recent order ids of every client from the stored
+// orders, returning the number of orders indexed
+fn rebuild_client_orders() -> usize {
+    // Drop the current index
+    CLIENT_ORDER_INDEX.with(|s| {
+        let keys: Vec<(u64, u64)> = s.borrow().iter().map(|(key, _)| key).collect();
+        for key in keys {
+            s.borrow_mut().remove(&key);
+        }
+    });
+
+    // Index every stored order under its client
+    let orders: Vec<(u64, u64)> = ORDER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(id, order)| (order.client_id, id))
+            .collect()
+    });
+    CLIENT_ORDER_INDEX.with(|s| {
+        for key in &orders {
+            s.borrow_mut().insert(*key, ());
+        }
+    });
+
+    // Refresh the recent order ids of every client from the index
+    let client_ids: Vec<u64> =
+        CLIENT_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
+    client_ids.into_iter().for_each(refresh_recent_order_ids);
+    orders.len()
+}
+
+// Set the recent order ids of a client from the client order index
+fn refresh_recent_order_ids(client_id: u64) {
+    CLIENT_STORAGE.with(|s| {
+        let client = s.borrow().get(&client_id);
+        if let Some(mut client) = client {
+            client.order_ids = client_order_ids(client_id);
+            client.order_ids.reverse();
+            client.order_ids.truncate(MAX_CLIENT_ORDER_IDS);
+            s.borrow_mut().insert(client_id, client);
+        }
+    });
+}
+
+// Move the orders of the storage used before line item snapshots, filling the snapshots from
+// the current menu and indexing them like newly placed orders
+fn migrate_legacy_orders() {
+    let stored: Vec<(u64, StoredOrderBytes)> =
+        LEGACY_ORDER_STORAGE.with(|s| s.borrow().iter().collect());
+    let mut client_ids: Vec<u64> = Vec::new();
+    for (id, bytes) in stored {
+        let order = bytes.migrate();
+        store_order(&order);
+        count_ordered_items(&order);
+        CLIENT_ORDER_INDEX.with(|s| s.borrow_mut().insert((order.client_id, order.id), ()));
+        client_ids.push(order.client_id);
+        LEGACY_ORDER_STORAGE.with(|s| s.borrow_mut().remove(&id));
+    }
+    client_ids.sort();
+    client_ids.dedup();
+    client_ids.into_iter().for_each(refresh_recent_order_ids);
+}
+
+// Take the reviews written before reviews recorded their author off client id 0, which may be
+// a real client; only they lack the delivered order of a verified purchase
+fn detach_anonymous_reviews() {
+    let attributed = CLIENT_REVIEW_INDEX.with(|s| index_ids(&s.borrow(), 0));
+    for id in attributed {
+        let review = REVIEW_STORAGE.with(|s| s.borrow().get(&id));
+        if let Some(mut review) = review.filter(|review| review.order_id.is_none()) {
+            CLIENT_REVIEW_INDEX.with(|s| s.borrow_mut().remove(&(0, id)));
+            review.client_id = None;
+            REVIEW_STORAGE.with(|s| s.borrow_mut().insert(id, review));
+        }
+    }
+}
+
+// Retrieve the ids of all orders of a client, oldest first
+fn client_order_ids(client_id: u64) -> Vec<u64> {
+    CLIENT_ORDER_INDEX.with(|s| index_ids(&s.borrow(), client_id))
+}
+
+// Retrieve all orders of a client through the client order index
+fn client_orders(client_id: u64) -> Vec<Order> {
+    client_order_ids(client_id)
+        .into_iter()
+        .filter_map(|order_id| ORDER_STORAGE.with(|s| s.borrow().get(&order_id)))
+        .collect()
+}
+
+// Retrieve the reviews written by a client
+fn client_reviews(client_id: u64) -> Vec<Review> {
+    CLIENT_REVIEW_INDEX
+        .with(|s| index_ids(&s.borrow(), client_id))
+        .into_iter()
+        .filter_map(|id| REVIEW_STORAGE.with(|s| s.borrow().get(&id)))
+        .collect()
+}
+
+// Check if an order reached the customer, including delivered orders refunded afterwards
+fn order_was_delivered(order: &Order) -> bool {
+    match order.status {
+        OrderStatus::Delivered => true,
+        OrderStatus::Refunded => ORDER_EVENTS.with(|s| {
+            s.borrow()
+                .range((order.id, 0)..=(order.id, u64::MAX))
+                .any(|(_, event)| event.status == OrderStatus::Delivered)
+        }),
+        _ => false,
+    }
+}
+
+// Collect everything stored about the client bound to the caller
+fn caller_data_export() -> Result<ClientDataExport, Error> {
+    let client_id = caller_client_id()?;
+    let client = CLIENT_STORAGE
+        .with(|s| s.borrow().get(&client_id))
+        .ok_or(Error::NotFound {
+            msg: format!("no client could be found for id: {}", client_id),
+        })?;
+
+    let orders = client_orders(client_id);
+    let mut delivery_locations: Vec<Location> = Vec::new();
+    for location in orders.iter().filter_map(|order| order.delivery_location) {
+        if !delivery_locations.contains(&location) {
+            delivery_locations.push(location);
+        }
+    }
+    let timeline: Vec<OrderEvent> = ORDER_EVENTS.with(|s| {
+        let events = s.borrow();
+        orders
+            .iter()
+            .flat_map(|order| {
+                events
+                    .range((order.id, 0)..=(order.id, u64::MAX))
+                    .map(|(_, event)| event)
+            })
+            .collect()
+    });
+
+    Ok(ClientDataExport {
+        addresses: vec![client.address.clone()],
+        reviews: client_reviews(client_id),
+        roles: roles_of(env::caller()),
+        client: ClientResponse::from(client),
+        orders,
+        delivery_locations,
+        timeline,
+        exported_at: env::time(),
+    })
+}
+
+// Check that a note given for an order event fits within 'MAX_NOTE_CHARS'
+fn validate_note(note: &Option<String>) -> Result<(), Error> {
+    match note {
+        Some(note)
+            if !validator::validate_length(note.as_str(), None, Some(MAX_NOTE_CHARS), None) =>
+        {
+            Err(Error::InvalidPayload {
+                msg: format!("a note must be at most {} characters", MAX_NOTE_CHARS),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Append a status change to the timeline of an order
+fn record_order_event(order_id: u64, status: OrderStatus, note: Option<String>) {
+    if status.is_final() {
+        queue_route_pruning(order_id, env::time());
+    }
+    ORDER_EVENTS.with(|s| {
+        let mut events = s.borrow_mut();
+        // Use the next sequence number after the last recorded event of this order
+        let seq = events
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .last()
+            .map_or(0, |((_, seq), _)| seq + 1);
+        events.insert(
+            (order_id, seq),
+            OrderEvent {
+                order_id,
+                status,
+                timestamp: env::time(),
+                actor: env::caller(),
+                note,
+            },
+        );
+    });
+}
+
+// Define structs for payload data (used in update calls)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct ClientPayload {
+    #[validate(length(min = 2, max = "MAX_CLIENT_NAME_CHARS"))]
+    name: String,
+    #[validate(length(min = 4, max = "MAX_CLIENT_ADDRESS_CHARS"))]
+    address: String,
+    #[validate(length(max = "MAX_CLIENT_PHONE_CHARS"))]
+    phone: String,
+    #[validate(length(max = "MAX_CLIENT_EMAIL_CHARS"))]
+    email: String,
+}
+
+// Fields of a 'Client' to change; unset fields are kept
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct ClientUpdate {
+    #[validate(length(min = 2, max = "MAX_CLIENT_NAME_CHARS"))]
+    name: Option<String>,
+    #[validate(length(min = 4, max = "MAX_CLIENT_ADDRESS_CHARS"))]
+    address: Option<String>,
+    #[validate(length(max = "MAX_CLIENT_PHONE_CHARS"))]
+    phone: Option<String>,
+    #[validate(length(max = "MAX_CLIENT_EMAIL_CHARS"))]
+    email: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderPayload {
+    client_id: u64,
+    items: Vec<OrderItem>,
+    delivery_location: Option<Location>,
+    // In the base currency
+    tip: Option<Money>,
+    promo_code: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderItem {
+    item_id: u64,
+    quantity: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct ReviewPayload {
+    item_id: u64,
+    // A delivered order of the caller that contains the item
+    order_id: u64,
+    #[validate(range(min = 1, max = 5))]
+    rating: u64,
+    #[validate(length(max = "MAX_REVIEW_COMMENT_CHARS"))]
+    comment: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct ItemPayload {
+    #[validate(length(min = 2), custom = "validate_item_name")]
+    name: String,
+    #[validate(length(min = 4), custom = "validate_item_description")]
+    description: String,
+    price: Money,
+    #[validate(custom = "validate_item_category")]
+    category: String,
+    restaurant_id: u64,
+    #[validate(range(min = 1, max = 240))]
+    prep_minutes: u32,
+    #[validate(custom = "validate_dietary_tags")]
+    dietary_tags: Vec<String>,
+}
+
+// Fields of an 'Item' to change; unset fields are kept
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate, Default)]
+struct ItemUpdate {
+    #[validate(length(min = 2), custom = "validate_item_name")]
+    name: Option<String>,
+    #[validate(length(min = 4), custom = "validate_item_description")]
+    description: Option<String>,
+    price: Option<Money>,
+    #[validate(custom = "validate_item_category")]
+    category: Option<String>,
+    #[validate(range(min = 1, max = 240))]
+    prep_minutes: Option<u32>,
+    #[validate(custom = "validate_dietary_tags")]
+    dietary_tags: Option<Vec<String>>,
+    availability: Option<ItemAvailability>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct PromotionPayload {
+    #[validate(custom = "validate_promo_code")]
+    code: String,
+    discount: Discount,
+    min_subtotal: Option<Money>,
+    valid_from: u64,
+    valid_until: Option<u64>,
+    #[validate(range(min = 1))]
+    max_redemptions: Option<u64>,
+    #[validate(range(min = 1))]
+    max_redemptions_per_client: Option<u64>,
+    first_order_only: bool,
+    #[validate(custom = "validate_promotion_categories")]
+    categories: Vec<String>,
+    #[validate(length(max = 16))]
+    restaurant_ids: Vec<u64>,
+}
+
+// Check that a promo code is short and made of letters, digits, dashes and underscores
+fn validate_promo_code(code: &str) -> Result<(), validator::ValidationError> {
+    let code = code.trim();
+    let valid_chars = code
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if code.len() < 3 || code.len() > MAX_PROMO_CODE_CHARS || !valid_chars {
+        return Err(validator::ValidationError::new("invalid_promo_code"));
+    }
+    Ok(())
+}
+
+// Check that a promotion covers a bounded number of non-empty categories
+fn validate_promotion_categories(categories: &[String]) -> Result<(), validator::ValidationError> {
+    if categories.len() > MAX_PROMOTION_CATEGORIES {
+        return Err(validator::ValidationError::new("too_many_categories"));
+    }
+    for category in categories {
+        let chars = category.trim().chars().count();
+        if chars == 0 || chars > MAX_CATEGORY_CHARS {
+            return Err(validator::ValidationError::new("invalid_category"));
+        }
+    }
+    Ok(())
+}
+
+// Check that an item has a bounded number of short, non-empty dietary tags, as stored
+fn validate_dietary_tags(tags: &[String]) -> Result<(), validator::ValidationError> {
+    if tags.len() > MAX_DIETARY_TAGS {
+        return Err(validator::ValidationError::new("too_many_dietary_tags"));
+    }
+    for tag in tags {
+        let bytes = tag.trim().to_lowercase().len();
+        if bytes == 0 || bytes > MAX_DIETARY_TAG_BYTES {
+            return Err(validator::ValidationError::new("invalid_dietary_tag"));
+        }
+    }
+    Ok(())
+}
+
+// Check the UTF-8 length of the text fields of an item
+fn validate_item_name(name: &str) -> Result<(), validator::ValidationError> {
+    match name.len() > MAX_ITEM_NAME_BYTES {
+        true => Err(validator::ValidationError::new("item_name_too_long")),
+        false => Ok(()),
+    }
+}
+
+fn validate_item_description(description: &str) -> Result<(), validator::ValidationError> {
+    match description.len() > MAX_ITEM_DESCRIPTION_BYTES {
+        true => Err(validator::ValidationError::new("item_description_too_long")),
+        false => Ok(()),
+    }
+}
+
+fn validate_item_category(category: &str) -> Result<(), validator::ValidationError> {
+    match category.len() > MAX_ITEM_CATEGORY_BYTES {
+        true => Err(validator::ValidationError::new("item_category_too_long")),
+        false => Ok(()),
+    }
+}
+
+// Lowercase and trim dietary tags so searches can match them exactly
+fn normalise_dietary_tags(tags: &[String]) -> Vec<String> {
+    let mut tags: Vec<String> = tags.iter().map(|tag| tag.trim().to_lowercase()).collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct RestaurantPayload {
+    #[validate(length(min = 2, max = "MAX_RESTAURANT_NAME_CHARS"))]
+    name: String,
+    #[validate(length(min = 4, max = "MAX_RESTAURANT_ADDRESS_CHARS"))]
+    address: String,
+    #[validate(range(min = -90.0, max = 90.0))]
+    latitude: f64,
+    #[validate(range(min = -180.0, max = 180.0))]
+    longitude: f64,
+    #[validate(length(max = "MAX_OPENING_HOURS"), custom = "validate_opening_hours")]
+    opening_hours: Vec<OpeningHours>,
+}
+
+// Check that every opening hours entry is a valid day and time range
+fn validate_opening_hours(hours: &[OpeningHours]) -> Result<(), validator::ValidationError> {
+    for entry in hours {
+        if entry.day > 6 || entry.opens_at >= entry.closes_at || entry.closes_at > 24 * 60 {
+            return Err(validator::ValidationError::new("invalid_opening_hours"));
+        }
+    }
+    Ok(())
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct CourierPayload {
+    #[validate(length(min = 2, max = "MAX_COURIER_NAME_CHARS"))]
+    name: String,
+    vehicle: VehicleType,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClientResponse {
+    id: u64,
+    name: String,
+    address: String,
+    phone: String,
+    email: String,
+    principal: Option<Principal>,
+    order_ids: Vec<u64>,
+    updated_at: Option<u64>,
+    deleted_at: Option<u64>,
+}
+
+impl From<Client> for ClientResponse {
+    fn from(client: Client) -> Self {
+        ClientResponse {
+            id: client.id,
+            name: client.name,
+            address: client.address,
+            phone: client.phone,
+            email: client.email,
+            principal: client.principal,
+            // The bounded list of recent orders; 'get_orders_by_client_id_v2' pages the rest
+            order_ids: client.order_ids,
+            updated_at: client.updated_at,
+            deleted_at: client.deleted_at,
+        }
+    }
+}
+
+// Define a struct for everything stored about a Client, returned by a data export
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClientDataExport {
+    client: ClientResponse,
+    orders: Vec<Order>,
+    reviews: Vec<Review>,
+    // The profile address followed by the distinct delivery locations of the orders
+    addresses: Vec<String>,
+    delivery_locations: Vec<Location>,
+    timeline: Vec<OrderEvent>,
+    roles: Vec<Role>,
+    exported_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ConfirmDeliveryPayload {
+    order_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DeleteReviewPayload {
+    review_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClaimClientPayload {
+    client_id: u64,
+    password: String,
+}
+
+// Read one page of the items shown on menus
+fn listed_items_page(storage: &StableBTreeMap<u64, Item, Memory>, page: &Page) -> PageResult<Item> {
+    let entries = storage
+        .range((page.lower_bound(), Bound::Unbounded))
+        .filter(|(_, item)| item.is_listed());
+    collect_page(entries, page)
+}
+
+// Define query functions to get a page of Food Items, optionally for a single Restaurant
+// Deprecated: use 'get_all_food_items_v2', which returns an empty page instead of 'NotFound'
+#[ic_cdk::query]
+fn get_all_food_items(restaurant_id: Option<u64>, page: Page) -> Result<PageResult<Item>, Error> {
+    // Page through the items of one restaurant through the index, or through the storage
+    let items: PageResult<Item> = match restaurant_id {
+        Some(restaurant_id) => RESTAURANT_ITEM_INDEX
+            .with(|s| index_page(&s.borrow(), restaurant_id, &page, listed_item)),
+        None => ITEM_STORAGE.with(|s| listed_items_page(&s.borrow(), &page)),
+    };
+
+    // Check if any items are found
+    match items.items.len() {
+        0 => Err(Error::NotFound {
+            msg: "no Food items for order could be found".to_string(),
+        }),
+        _ => Ok(items),
+    }
+}
+
+// Define query functions to get a page of Food Items, optionally for a single Restaurant,
+// with the total number of matching items
+#[ic_cdk::query]
+fn get_all_food_items_v2(
+    restaurant_id: Option<u64>,
+    page: Page,
+) -> Result<ListResult<Item>, Error> {
+    let items: ListResult<Item> = match restaurant_id {
+        Some(restaurant_id) => RESTAURANT_ITEM_INDEX.with(|s| {
+            let index = s.borrow();
+            let total = capped_total(
+                index_range(&index, restaurant_id, None).filter(|id| listed_item(*id).is_some()),
+            );
+            index_page(&index, restaurant_id, &page, listed_item).with_total(total)
+        }),
+        None => ITEM_STORAGE.with(|s| {
+            let storage = s.borrow();
+            let total = capped_total(storage.iter().filter(|(_, item)| item.is_listed()));
+            listed_items_page(&storage, &page).with_total(total)
+        }),
+    };
+
+    Ok(items)
+}
+
+// Define query functions to get a specific Food Item by id
+#[ic_cdk::query]
+fn get_food_item_by_id(id: u64) -> Result<Item, Error> {
+    // Retrieve the item from the storage
+    let item: Option<Item> = ITEM_STORAGE.with(|s| s.borrow().get(&id));
+
+    // Check if the item is found
+    match item {
+        Some(item) => Ok(item),
+        None => Err(Error::NotFound {
+            msg: format!("no Food item could be found for id: {}", id),
+        }),
+    }
+}
+
+// Define update functions to create a new Food item
+#[ic_cdk::update]
+fn create_food_item(payload: ItemPayload) -> Result<Item, Error> {
+    require_role(&[Role::RestaurantStaff])?;
+
+    // Validate the payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    require_base_currency(&payload.price, "price")?;
+
+    // Check if the restaurant exists and belongs to the caller
+    match RESTAURANT_STORAGE.with(|s| s.borrow().get(&payload.restaurant_id)) {
+        Some(restaurant) => require_restaurant_owner(&restaurant)?,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!(
+                    "no restaurant could be found for id: {}",
+                    payload.restaurant_id
+                ),
+            })
+        }
+    }
+
+    // Retrieve the next id from the storage
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    // Create a new Food item
+    let item: Item = Item {
+        id,
+        name: payload.name,
+        description: payload.description,
+        price: payload.price,
+        category: payload.category,
+        restaurant_id: Some(payload.restaurant_id),
+        prep_minutes: Some(payload.prep_minutes),
+        dietary_tags: Some(normalise_dietary_tags(&payload.dietary_tags)),
+        updated_at: Some(env::time()),
+        availability: Some(ItemAvailability::Available),
+        archived_at: None,
+        rating: None,
+    };
+
+    // Store the new Food item in the storage
+    store_item(&item);
+
+    // Return the new Food item
+    Ok(item)
+}
+
+// Define update functions to change the details of a Food Item
+#[ic_cdk::update]
+fn update_food_item(id: u64, payload: ItemUpdate) -> Result<Item, Error> {
+    require_role(&[Role::RestaurantStaff])?;
+
+    // Validate the payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    if let Some(price) = &payload.price {
+        require_base_currency(price, "price")?;
+    }
+
+    // Retrieve the item from the storage
+    let mut item = match ITEM_STORAGE.with(|s| s.borrow().get(&id)) {
+        Some(item) => item,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("no Food item could be found for id: {}", id),
+            })
+        }
+    };
+
+    // Check if the item belongs to a restaurant of the caller
+    require_owner_of_restaurant(item.restaurant_id)?;
+
+    // Check if the item is still on the menu
+    if item.is_archived() {
+        return Err(Error::InvalidPayload {
+            msg: format!("Food item id: {} is archived", id),
+        });
+    }
+
+    // Apply the fields that are set
+    if let Some(name) = payload.name {
+        item.name = name;
+    }
+    if let Some(description) = payload.description {
+        item.description = description;
+    }
+    if let Some(price) = payload.price {
+        item.price = price;
+    }
+    if let Some(category) = payload.category {
+        item.category = category;
+    }
+    if let Some(prep_minutes) = payload.prep_minutes {
+        item.prep_minutes = Some(prep_minutes);
+    }
+    if let Some(dietary_tags) = payload.dietary_tags {
+        item.dietary_tags = Some(normalise_dietary_tags(&dietary_tags));
+    }
+    if let Some(availability) = payload.availability {
+        item.availability = Some(availability);
+    }
+    item.updated_at = Some(env::time());
+
+    // Store the updated Food item, refreshing its index entries
+    store_item(&item);
+
+    Ok(item)
+}
+
+// Define update functions to archive a specific Food Item by id, keeping it for order history
+#[ic_cdk::update]
+fn delete_food_item_by_id(id: u64) -> Result<String, Error> {
+    require_role(&[Role::RestaurantStaff])?;
+
+    //    check if the item is exists
+    let mut item = match ITEM_STORAGE.with(|s| s.borrow().get(&id)) {
+        Some(item) => item,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Food item id: {} could not be found", id),
+            })
+        }
+    };
+
+    // Check if the item belongs to a restaurant of the caller
+    require_owner_of_restaurant(item.restaurant_id)?;
+
+    // Archive the Food item, keeping its reviews
+    if !item.is_archived() {
+        let now = env::time();
+        item.archived_at = Some(now);
+        item.updated_at = Some(now);
+        store_item(&item);
+    }
+
+    Ok(format!("Food item id: {} archived", id))
+}
+
+// Define update functions to permanently remove an archived Food Item and its reviews
+#[ic_cdk::update]
+fn purge_food_item(id: u64) -> Result<String, Error> {
+    require_role(&[Role::Admin])?;
+
+    // Check if the item exists and has been archived
+    match ITEM_STORAGE.with(|s| s.borrow().get(&id)) {
+        Some(item) if item.is_archived() => {}
+        Some(_) => {
+            return Err(Error::InvalidPayload {
+                msg: format!("Food item id: {} must be archived before it is purged", id),
+            })
+        }
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Food item id: {} could not be found", id),
+            })
+        }
+    }
+
+    // delete item reviews
+    let review_ids: Vec<u64> = ITEM_REVIEW_INDEX.with(|s| index_ids(&s.borrow(), id));
+    for review_id in review_ids {
+        remove_review(review_id);
+    }
+    ITEM_ORDER_COUNTS.with(|s| s.borrow_mut().remove(&id));
+
+    // Delete the Food item from the storage
+    match remove_item(id) {
+        Some(_) => Ok(format!("Food item id: {} purged", id)),
+        None => Err(Error::NotFound {
+            msg: format!("Food item id: {} could not be purged", id),
+        }),
+    }
+}
+
+//  get food items by category, optionally for a single restaurant, up to 'MAX_PAGE_LIMIT' items
+// Deprecated: use 'get_food_items_by_category_v2', which returns an empty page instead of 'NotFound'
+// and pages through every item
+#[ic_cdk::query]
+fn get_food_items_by_category(
+    category: String,
+    restaurant_id: Option<u64>,
+) -> Result<Vec<Item>, Error> {
+    // Retrieve the first items of the category through the index
+    let first_page = Page {
+        start_after: None,
+        limit: MAX_PAGE_LIMIT,
+    };
+    let items_by_category: Vec<Item> = CATEGORY_ITEM_INDEX.with(|s| {
+        index_page(
+            &s.borrow(),
+            category_key(&category),
+            &first_page,
+            listed_item_of(restaurant_id),
+        )
+        .items
+    });
+
+    // Check if any items are found
+    match items_by_category.len() {
+        0 => Err(Error::NotFound {
+            msg: format!("no Food items for category: {} could be found", category),
+        }),
+        _ => Ok(items_by_category),
+    }
+}
+
+// Define query functions to get a page of Food Items in a category, optionally for a single
+// Restaurant, with the total number of matching items
+#[ic_cdk::query]
+fn get_food_items_by_category_v2(
+    category: String,
+    restaurant_id: Option<u64>,
+    page: Page,
+) -> Result<ListResult<Item>, Error> {
+    // Skip unlisted items and items of other restaurants while walking the category index
+    let get_item = listed_item_of(restaurant_id);
+
+    let items: ListResult<Item> = CATEGORY_ITEM_INDEX.with(|s| {
+        let index = s.borrow();
+        let key = category_key(&category);
+        let total =
+            capped_total(index_range(&index, key, None).filter(|id| get_item(*id).is_some()));
+        index_page(&index, key, &page, get_item).with_total(total)
+    });
+
+    Ok(items)
+}
+
+// Define query functions to search the menu with filters and sorting
+#[ic_cdk::query]
+fn search_food_items(query: SearchQuery) -> Result<SearchResult, Error> {
+    if let (Some(min), Some(max)) = (query.min_price, query.max_price) {
+        if min.currency != max.currency {
+            return Err(Error::InvalidPayload {
+                msg: "min_price and max_price must use the same currency".to_string(),
+            });
+        }
+        if min.amount_minor > max.amount_minor {
+            return Err(Error::InvalidPayload {
+                msg: "min_price cannot be above max_price".to_string(),
+            });
+        }
+    }
+
+    // Narrow the candidates with the most selective index available
+    let tokens = query.text.as_deref().map(search_tokens).unwrap_or_default();
+    if query.text.is_some() && tokens.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "text must contain at least one word to search for".to_string(),
+        });
+    }
+    let candidates: Vec<u64> = if !tokens.is_empty() {
+        SEARCH_INDEX.with(|s| {
+            let index = s.borrow();
+            let mut postings: Vec<Vec<u64>> = tokens
+                .iter()
+                .map(|token| index_ids(&index, *token))
+                .collect();
+            postings.sort_by_key(|ids| ids.len());
+            let mut matches = postings.remove(0);
+            for ids in &postings {
+                let ids: HashSet<&u64> = ids.iter().collect();
+                matches.retain(|id| ids.contains(id));
+            }
+            matches
+        })
+    } else if let Some(category) = &query.category {
+        CATEGORY_ITEM_INDEX.with(|s| index_ids(&s.borrow(), category_key(category)))
+    } else if let Some(restaurant_id) = query.restaurant_id {
+        RESTAURANT_ITEM_INDEX.with(|s| index_ids(&s.borrow(), restaurant_id))
+    } else {
+        ITEM_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect())
+    };
+
+    // Apply the remaining filters
+    let category = query.category.as_deref().map(category_key);
+    let dietary_tags = normalise_dietary_tags(&query.dietary_tags);
+    let mut active_restaurants: HashMap<u64, bool> = HashMap::new();
+    let mut hits: Vec<SearchHit> = Vec::new();
+    for id in candidates {
+        let item = match listed_item(id) {
+            Some(item) => item,
+            None => continue,
+        };
+        if category.is_some_and(|category| category_key(&item.category) != category)
+            || query.restaurant_id.is_some() && item.restaurant_id != query.restaurant_id
+            || query.min_price.is_some_and(|min| {
+                item.price.currency != min.currency || item.price.amount_minor < min.amount_minor
+            })
+            || query.max_price.is_some_and(|max| {
+                item.price.currency != max.currency || item.price.amount_minor > max.amount_minor
+            })
+        {
+            continue;
+        }
+        let item_tags = item.dietary_tags.as_deref().unwrap_or_default();
+        if !dietary_tags.iter().all(|tag| item_tags.contains(tag)) {
+            continue;
+        }
+        if query.available_only {
+            let available = item.is_orderable()
+                && item.restaurant_id.is_some_and(|restaurant_id| {
+                    *active_restaurants.entry(restaurant_id).or_insert_with(|| {
+                        RESTAURANT_STORAGE
+                            .with(|s| s.borrow().get(&restaurant_id))
+                            .is_some_and(|restaurant| restaurant.active)
+                    })
+                });
+            if !available {
+                continue;
+            }
+        }
+        let average_rating = item.rating.and_then(|stats| stats.average());
+        if let Some(min_rating) = query.min_rating {
+            if !average_rating.is_some_and(|rating| rating >= min_rating) {
+                continue;
+            }
+        }
+        let order_count = ITEM_ORDER_COUNTS.with(|s| s.borrow().get(&item.id).unwrap_or(0));
+        hits.push(SearchHit {
+            item,
+            average_rating,
+            order_count,
+        });
+    }
+
+    // Sort the matches, keeping id order between equal entries; prices are grouped by currency
+    hits.sort_by_key(|hit| hit.item.id);
+    let price = |hit: &SearchHit| (hit.item.price.currency, hit.item.price.amount_minor);
+    match query.sort_by {
+        Some(SearchSort::PriceAscending) => hits.sort_by_key(price),
+        Some(SearchSort::PriceDescending) => hits.sort_by_key(|hit| std::cmp::Reverse(price(hit))),
+        Some(SearchSort::Rating) => hits.sort_by(|a, b| {
+            let rating = |hit: &SearchHit| hit.item.rating.unwrap_or_default().bayesian_average();
+            rating(b).total_cmp(&rating(a))
+        }),
+        Some(SearchSort::Popularity) => hits.sort_by_key(|hit| std::cmp::Reverse(hit.order_count)),
+        None => {}
+    }
+
+    // Resume after the cursor item; in id order it may have stopped matching since
+    let start = match query.page.start_after {
+        None => 0,
+        Some(id) if query.sort_by.is_none() => hits.partition_point(|hit| hit.item.id <= id),
+        Some(id) => match hits.iter().position(|hit| hit.item.id == id) {
+            Some(position) => position + 1,
+            None => {
+                return Err(Error::InvalidPayload {
+                    msg: format!("item id: {} no longer matches the search", id),
+                })
+            }
+        },
+    };
+    let total = hits.len() as u64;
+    let page = collect_page(
+        hits.into_iter().skip(start).map(|hit| (hit.item.id, hit)),
+        &query.page,
+    );
+
+    Ok(SearchResult {
+        hits: page.items,
+        next_cursor: page.next_cursor,
+        total,
+    })
+}
+
+// Define query functions to get a page of Restaurants
+#[ic_cdk::query]
+fn get_all_restaurants(page: Page) -> Result<PageResult<Restaurant>, Error> {
+    // Retrieve the requested slice of restaurants from the storage
+    let restaurants: PageResult<Restaurant> =
+        RESTAURANT_STORAGE.with(|s| page_of(&s.borrow(), &page));
+
+    // Check if any restaurants are found
+    match restaurants.items.len() {
+        0 => Err(Error::NotFound {
+            msg: "no restaurants could be found".to_string(),
+        }),
+        _ => Ok(restaurants),
+    }
+}
+
+// Define query functions to get a specific Restaurant by id
+#[ic_cdk::query]
+fn get_restaurant_by_id(id: u64) -> Result<Restaurant, Error> {
+    RESTAURANT_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("no restaurant could be found for id: {}", id),
+        })
+}
+
+// Define update functions to create a new Restaurant owned by the caller
+#[ic_cdk::update]
+fn create_restaurant(payload: RestaurantPayload) -> Result<Restaurant, Error> {
+    require_role(&[Role::RestaurantStaff])?;
+
+    // Validate the payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Retrieve the next id from the storage
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    // Create a new Restaurant
+    let restaurant: Restaurant = Restaurant {
+        id,
+        name: payload.name,
+        address: payload.address,
+        latitude: payload.latitude,
+        longitude: payload.longitude,
+        opening_hours: payload.opening_hours,
+        owner: env::caller(),
+        active: true,
+        rating: None,
+    };
+
+    // Store the new Restaurant in the storage
+    RESTAURANT_STORAGE.with(|s| s.borrow_mut().insert(id, restaurant.clone()));
+
+    Ok(restaurant)
+}
+
+// Define update functions to update the details of a Restaurant
+#[ic_cdk::update]
+fn update_restaurant(id: u64, payload: RestaurantPayload) -> Result<Restaurant, Error> {
+    // Validate the payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Retrieve the restaurant from the storage
+    let restaurant = get_restaurant_by_id(id)?;
+    require_restaurant_owner(&restaurant)?;
+
+    // Replace the details, keeping the owner and active flag
+    let restaurant = Restaurant {
+        name: payload.name,
+        address: payload.address,
+        latitude: payload.latitude,
+        longitude: payload.longitude,
+        opening_hours: payload.opening_hours,
+        ..restaurant
+    };
+    RESTAURANT_STORAGE.with(|s| s.borrow_mut().insert(id, restaurant.clone()));
+
+    Ok(restaurant)
+}
+
+// Define update functions to open or close a Restaurant for new orders
+#[ic_cdk::update]
+fn set_restaurant_active(id: u64, active: bool) -> Result<Restaurant, Error> {
+    // Retrieve the restaurant from the storage
+    let mut restaurant = get_restaurant_by_id(id)?;
+    require_restaurant_owner(&restaurant)?;
+
+    restaurant.active = active;
+    RESTAURANT_STORAGE.with(|s| s.borrow_mut().insert(id, restaurant.clone()));
+
+    Ok(restaurant)
+}
+
+// Define update functions to delete a Restaurant without menu items
+#[ic_cdk::update]
+fn delete_restaurant(id: u64) -> Result<String, Error> {
+    // Retrieve the restaurant from the storage
+    let restaurant = get_restaurant_by_id(id)?;
+    require_restaurant_owner(&restaurant)?;
+
+    // Check if the restaurant still sells any items
+    let has_items = RESTAURANT_ITEM_INDEX.with(|s| !index_ids(&s.borrow(), id).is_empty());
+    if has_items {
+        return Err(Error::InvalidPayload {
+            msg: format!("restaurant id: {} still has menu items", id),
+        });
+    }
+
+    // Delete the Restaurant from the storage
+    RESTAURANT_STORAGE.with(|s| s.borrow_mut().remove(&id));
+    Ok(format!("Restaurant id: {} deleted", id))
+}
+
+// Define query functions to get a page of Orders
+// Deprecated: use 'get_all_orders_v2', which returns an empty page instead of 'NotFound'
+#[ic_cdk::query]
+fn get_all_orders(page: Page) -> Result<PageResult<Order>, Error> {
+    require_role(&[Role::RestaurantStaff])?;
+
+    // Retrieve the requested slice of orders from the storage
+    let orders: PageResult<Order> = ORDER_STORAGE.with(|s| page_of(&s.borrow(), &page));
+
+    // Check if any orders are found
+    match orders.items.len() {
+        0 => Err(Error::NotFound {
+            msg: "no orders could be found".to_string(),
+        }),
+        _ => Ok(orders),
+    }
+}
+
+// Define query functions to get a page of Orders with the total number of orders
+#[ic_cdk::query]
+fn get_all_orders_v2(page: Page) -> Result<ListResult<Order>, Error> {
+    require_role(&[Role::RestaurantStaff])?;
+
+    // Retrieve the requested slice of orders from the storage
+    let orders: ListResult<Order> = ORDER_STORAGE.with(|s| {
+        let storage = s.borrow();
+        page_of(&storage, &page).with_total(storage.len())
+    });
+
+    Ok(orders)
+}
+
+// Define query functions to get a specific Order by id
+#[ic_cdk::query]
+fn get_order_by_id(id: u64) -> Result<Order, Error> {
+    // Retrieve the order from the storage
+    let order: Option<Order> = ORDER_STORAGE.with(|s| s.borrow().get(&id));
+
+    // Check if the order is found
+    match order {
+        Some(order) => {
+            require_order_party(&order)?;
+            Ok(order)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("no order could be found for id: {}", id),
+        }),
+    }
+}
+
+// Define query functions to get all Orders for a specific Client
+// Deprecated: use 'get_orders_by_client_id_v2', which returns an empty page instead of 'NotFound'
+#[ic_cdk::query]
+fn get_orders_by_client_id(client_id: u64) -> Result<Vec<Order>, Error> {
+    require_owner_or_role(client_id, &[Role::Admin])?;
+
+    // Retrieve the orders of the client through the index
+    let orders_by_client_id: Vec<Order> = client_orders(client_id);
+
+    // add item quantity to Item
+    // Check if any orders are found
+    match orders_by_client_id.len() {
+        0 => Err(Error::NotFound {
+            msg: format!("no orders could be found for client_id: {}", client_id),
+        }),
+        _ => Ok(orders_by_client_id),
+    }
+}
+
+// Define query functions to get a page of Orders for a specific Client with the total number
+// of orders of the client
+#[ic_cdk::query]
+fn get_orders_by_client_id_v2(client_id: u64, page: Page) -> Result<ListResult<Order>, Error> {
+    require_owner_or_role(client_id, &[Role::Admin])?;
+
+    // Retrieve the orders of the client through the index
+    let orders: ListResult<Order> = CLIENT_ORDER_INDEX.with(|s| {
+        let index = s.borrow();
+        let total = capped_total(index_range(&index, client_id, None));
+        index_page(&index, client_id, &page, |id| {
+            ORDER_STORAGE.with(|s| s.borrow().get(&id))
+        })
+        .with_total(total)
+    });
+
+    Ok(orders)
+}
+
+// Define query functions to get a page of the Orders in a specific status
+#[ic_cdk::query]
+fn get_orders_by_status(status: OrderStatus, page: Page) -> Result<PageResult<Order>, Error> {
+    require_role(&[Role::RestaurantStaff, Role::Courier])?;
+
+    // Retrieve the orders in the status through the index
+    let orders: PageResult<Order> = ORDER_STATUS_INDEX.with(|s| {
+        index_page(&s.borrow(), status as u64, &page, |id| {
+            ORDER_STORAGE.with(|s| s.borrow().get(&id))
+        })
+    });
+
+    Ok(orders)
+}
+
+// Define query functions to get a page of the Orders of the calling Client
+#[ic_cdk::query]
+fn get_my_orders(page: Page) -> Result<PageResult<Order>, Error> {
+    // Resolve the client bound to the caller
+    let client_id = caller_client_id()?;
+
+    // Retrieve the orders of the client through the index
+    let orders: PageResult<Order> = CLIENT_ORDER_INDEX.with(|s| {
+        index_page(&s.borrow(), client_id, &page, |id| {
+            ORDER_STORAGE.with(|s| s.borrow().get(&id))
+        })
+    });
+
+    Ok(orders)
+}
+
+// Define query functions to confirm order delivery
+#[ic_cdk::update]
+fn confirm_delivery(payload: ConfirmDeliveryPayload) -> Result<String, Error> {
+    // Resolve the client bound to the caller
+    let client_id = caller_client_id()?;
+
+    // Retrieve the order from the storage
+    let order: Option<Order> = ORDER_STORAGE.with(|s| s.borrow().get(&payload.order_id));
+
+    // Check if the order is found
+    match order {
+        Some(mut order) => {
+            // Check if the order belongs to the caller
+            if order.client_id != client_id {
+                return Err(Error::Unauthorized {
+                    msg: format!("order id: {} does not belong to the caller", order.id),
+                });
+            }
+
+            // Check if the order is already delivered
+            if order.delivered {
+                return Err(Error::AlreadyDelivered {
+                    msg: format!("order id: {} is already delivered", order.id),
+                });
+            }
+
+            // Move the order to the delivered state
+            order.set_status(OrderStatus::Delivered)?;
+            store_order(&order);
+            record_order_event(order.id, order.status, None);
+            refresh_order_eta(&order);
+            record_payouts(&order);
+
+            Ok(format!("order id: {} is delivered", order.id))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("no order could be found for id: {}", payload.order_id),
+        }),
+    }
+}
+
+// Define query functions to get a page of the status timeline of an Order; the cursor is the
+// sequence number of the last event returned
+#[ic_cdk::query]
+fn get_order_timeline(order_id: u64, page: Page) -> Result<PageResult<OrderEvent>, Error> {
+    // Check if the order exists and the caller may see it
+    match ORDER_STORAGE.with(|s| s.borrow().get(&order_id)) {
+        Some(order) => require_order_party(&order)?,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("no order could be found for id: {}", order_id),
+            })
+        }
+    }
+
+    // Retrieve the events of the order in the order they were recorded
+    let lower = match page.start_after {
+        Some(seq) => Bound::Excluded((order_id, seq)),
+        None => Bound::Included((order_id, 0)),
+    };
+    let events: PageResult<OrderEvent> = ORDER_EVENTS.with(|s| {
+        let events = s.borrow();
+        let entries = events
+            .range((lower, Bound::Included((order_id, u64::MAX))))
+            .map(|((_, seq), event)| (seq, event));
+        collect_page(entries, &page)
+    });
+
+    Ok(events)
+}
+
+// Define update functions to update order status
+#[ic_cdk::update]
+fn update_order_status(
+    order_id: u64,
+    status: OrderStatus,
+    note: Option<String>,
+) -> Result<String, Error> {
+    require_role(&[Role::RestaurantStaff, Role::Courier])?;
+    validate_note(&note)?;
+
+    // Retrieve the order from the storage
+    let order: Option<Order> = ORDER_STORAGE.with(|s| s.borrow().get(&order_id));
+
+    // Check if the order is found
+    match order {
+        Some(mut order) => {
+            // Cancellations carry a reason and may create a refund
+            if status == OrderStatus::Cancelled {
+                return Err(Error::InvalidTransition {
+                    msg: format!("use cancel_order to cancel order id: {}", order.id),
+                });
+            }
+
+            // Payments and refunds of captured payments go through the ledger
+            if status == OrderStatus::Paid {
+                return Err(Error::InvalidTransition {
+                    msg: format!("use pay_order to pay order id: {}", order.id),
+                });
+            }
+            if status == OrderStatus::Refunded && order.amount_captured.is_some() {
+                return Err(Error::InvalidTransition {
+                    msg: format!("use settle_refund to refund order id: {}", order.id),
+                });
+            }
+            let ledger = CONFIG.with(|c| c.borrow().get().ledger_canister_id);
+            if ledger.is_some() && order.status == OrderStatus::Placed {
+                return Err(Error::InvalidTransition {
+                    msg: format!("order id: {} must be paid before it moves on", order.id),
+                });
+            }
+
+            // Only the assigned courier may report pickup, transit and delivery, and only the
+            // restaurant of the order may report anything else
+            if matches!(
+                status,
+                OrderStatus::PickedUp | OrderStatus::InTransit | OrderStatus::Delivered
+            ) {
+                require_assigned_courier(&order)?;
+            } else {
+                require_owner_of_restaurant(order.restaurant_id)?;
+            }
+
+            // Update the order status if the transition is allowed
+            order.set_status(status)?;
+            store_order(&order);
+            record_order_event(order.id, status, note);
+            refresh_order_eta(&order);
+            if status == OrderStatus::Delivered {
+                record_payouts(&order);
+            }
+
+            Ok(format!(
+                "order id: {} status updated to {:?}",
+                order.id, status
+            ))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("no order could be found for id: {}", order_id),
+        }),
+    }
+}
+
+// Define update functions to cancel an Order: its client may cancel before the restaurant
+// accepts it and within the cancellation window, the restaurant before pickup, admins anytime
+#[ic_cdk::update]
+fn cancel_order(
+    order_id: u64,
+    reason: CancellationReason,
+    note: Option<String>,
+) -> Result<Order, Error> {
+    let caller = env::caller();
+    validate_note(&note)?;
+
+    // Retrieve the order from the storage
+    let mut order = match ORDER_STORAGE.with(|s| s.borrow().get(&order_id)) {
+        Some(order) => order,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("no order could be found for id: {}", order_id),
+            })
+        }
+    };
+    if order.status.is_final() {
+        return Err(Error::InvalidTransition {
+            msg: format!("order id: {} is already {:?}", order.id, order.status),
+        });
+    }
+
+    // Check if the caller may cancel the order at its current stage
+    let restaurant = order
+        .restaurant_id
+        .and_then(|restaurant_id| RESTAURANT_STORAGE.with(|s| s.borrow().get(&restaurant_id)));
+    let before_pickup = !matches!(order.status, OrderStatus::PickedUp | OrderStatus::InTransit);
+    if has_role(caller, Role::Admin) {
+        // Admins may cancel at any stage
+    } else if has_role(caller, Role::RestaurantStaff)
+        && restaurant.as_ref().is_some_and(|r| r.owner == caller)
+    {
+        if !before_pickup {
+            return Err(Error::Forbidden {
+                msg: format!("order id: {} has already been picked up", order.id),
+            });
+        }
+    } else if caller_client_id().ok() == Some(order.client_id) {
+        if !matches!(order.status, OrderStatus::Placed | OrderStatus::Paid) {
+            return Err(Error::Forbidden {
+                msg: format!(
+                    "order id: {} has already been accepted by the restaurant",
+                    order.id
+                ),
+            });
+        }
+        let window = CONFIG.with(|c| c.borrow().get().cancellation_window_nanos());
+        let placed_at = order_placed_at(order.id).unwrap_or(0);
+        if env::time().saturating_sub(placed_at) > window {
+            return Err(Error::Forbidden {
+                msg: format!(
+                    "the cancellation window of order id: {} has passed",
+                    order.id
+                ),
+            });
+        }
+    } else {
+        return Err(Error::Forbidden {
+            msg: format!("principal: {} cannot cancel order id: {}", caller, order.id),
+        });
+    }
+
+    // Cancel the order
+    let now = env::time();
+    order.set_status(OrderStatus::Cancelled)?;
+    order.cancellation = Some(Cancellation {
+        reason,
+        note: note.clone(),
+        cancelled_by: caller,
+        cancelled_at: now,
+    });
+    // Release the courier, so the order leaves its list and it can no longer report on it; the
+    // cancellation event says so next to the caller's note
+    let event_note = match (order.assignment.take(), note) {
+        (Some(assignment), Some(note)) => Some(format!(
+            "{} (courier id: {} released)",
+            note, assignment.courier_id
+        )),
+        (Some(assignment), None) => Some(format!("courier id: {} released", assignment.courier_id)),
+        (None, note) => note,
+    };
+    store_order(&order);
+    record_order_event(order.id, order.status, event_note);
+    refresh_order_eta(&order);
+
+    // Owe the client what was already collected, and let them use the promo code again
+    create_refund(&order, reason);
+    if let Some(promotion_id) = order.promotion_id {
+        update_redemptions(promotion_id, order.client_id, false);
+    }
+
+    Ok(order)
+}
+
+// Define query functions to get the Refund of a cancelled Order
+#[ic_cdk::query]
+fn get_order_refund(order_id: u64) -> Result<Refund, Error> {
+    // Check if the order exists and the caller may see it
+    match ORDER_STORAGE.with(|s| s.borrow().get(&order_id)) {
+        Some(order) => require_order_party(&order)?,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("no order could be found for id: {}", order_id),
+            })
+        }
+    }
+
+    REFUND_STORAGE
+        .with(|s| s.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!("no refund could be found for order id: {}", order_id),
+        })
+}
+
+// Define update functions to pay for an Order of the caller from the caller's ledger account,
+// using an allowance the caller approved for this canister
+#[ic_cdk::update]
+async fn pay_order(order_id: u64) -> Result<Order, Error> {
+    let ledger = payment_ledger()?;
+    let client_id = caller_client_id()?;
+
+    // Check if the order belongs to the caller and still waits for payment
+    let order = match ORDER_STORAGE.with(|s| s.borrow().get(&order_id)) {
+        Some(order) if order.client_id == client_id => order,
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!(
+                    "no order could be found for id: {} and the caller",
+                    order_id
+                ),
+            })
+        }
+    };
+    if order.status != OrderStatus::Placed || order.amount_captured.is_some() {
+        return Err(Error::InvalidTransition {
+            msg: format!(
+                "order id: {} cannot be paid while {:?}",
+                order.id, order.status
+            ),
+        });
+    }
+    // The ledger holds the base currency only
+    if order.total.currency != base_currency() {
+        return Err(Error::PaymentFailed {
+            msg: format!(
+                "order id: {} is priced in {:?}, but the ledger takes {:?}",
+                order.id,
+                order.total.currency,
+                base_currency()
+            ),
+        });
+    }
+
+    // Move the order total from the caller to this canister
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: env::caller(),
+            subaccount: None,
+        },
+        to: Account {
+            owner: env::id(),
+            subaccount: None,
+        },
+        amount: Nat::from(order.total.amount_minor),
+        fee: None,
+        memo: Some(order.id.to_be_bytes().to_vec()),
+        created_at_time: Some(env::time()),
+    };
+    begin_ledger_call(order.id)?;
+    let result = icrc::icrc2_transfer_from(ledger, args).await;
+    end_ledger_call(order.id);
+    let block_index = match result {
+        Ok((Ok(block_index),)) => block_index,
+        Ok((Err(e),)) => {
+            return Err(Error::PaymentFailed {
+                msg: format!("ledger rejected the payment: {:?}", e),
+            })
+        }
+        Err((code, msg)) => {
+            return Err(Error::PaymentFailed {
+                msg: format!("ledger call failed: {:?} {}", code, msg),
+            })
+        }
+    };
+
+    // The order may have changed while the ledger was called, so read it again
+    let mut order = ORDER_STORAGE
+        .with(|s| s.borrow().get(&order_id))
+        .expect("orders are never removed");
+    order.amount_captured = Some(order.total);
+    record_ledger_entry(LedgerEntry {
+        order_id: order.id,
+        kind: LedgerEntryKind::Payment,
+        counterparty: env::caller(),
+        amount: order.total,
+        block_index: Some(block_index),
+        created_at: env::time(),
+    });
+    if order.status == OrderStatus::Placed {
+        order.set_status(OrderStatus::Paid)?;
+        record_order_event(order.id, order.status, None);
+    } else if let Some(cancellation) = &order.cancellation {
+        // Cancelled while paying, so the payment is owed back
+        create_refund(&order, cancellation.reason);
+    }
+    store_order(&order);
+
+    Ok(order)
+}
+
+// Define update functions to pay back the Refund of a cancelled Order to its client
+#[ic_cdk::update]
+async fn settle_refund(order_id: u64) -> Result<Refund, Error> {
+    require_role(&[Role::Admin])?;
+    let ledger = payment_ledger()?;
+
+    // Check if the refund is still owed and the client can receive it
+    let refund = get_order_refund(order_id)?;
+    if refund.settled_at.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!("the refund of order id: {} is already settled", order_id),
+        });
+    }
+    let recipient = CLIENT_STORAGE
+        .with(|s| s.borrow().get(&refund.client_id))
+        .and_then(|client| client.principal)
+        .ok_or(Error::InvalidPayload {
+            msg: format!("client id: {} has no principal to refund", refund.client_id),
+        })?;
+
+    // Move the refund from this canister to the client
+    let args = TransferArg {
+        from_subaccount: None,
+        to: Account {
+            owner: recipient,
+            subaccount: None,
+        },
+        amount: Nat::from(refund.amount.amount_minor),
+        fee: None,
+        memo: Some(order_id.to_be_bytes().to_vec()),
+        created_at_time: Some(env::time()),
+    };
+    begin_ledger_call(order_id)?;
+    let result = icrc::icrc1_transfer(ledger, args).await;
+    end_ledger_call(order_id);
+    let block_index = match result {
+        Ok((Ok(block_index),)) => block_index,
+        Ok((Err(e),)) => {
+            return Err(Error::PaymentFailed {
+                msg: format!("ledger rejected the refund: {:?}", e),
+            })
+        }
+        Err((code, msg)) => {
+            return Err(Error::PaymentFailed {
+                msg: format!("ledger call failed: {:?} {}", code, msg),
+            })
+        }
+    };
+
+    // Record the settlement and move the order to the refunded state
+    let now = env::time();
+    let refund = Refund {
+        settled_at: Some(now),
+        ..refund
+    };
+    REFUND_STORAGE.with(|s| s.borrow_mut().insert(order_id, refund.clone()));
+    record_ledger_entry(LedgerEntry {
+        order_id,
+        kind: LedgerEntryKind::Refund,
+        counterparty: recipient,
+        amount: refund.amount,
+        block_index: Some(block_index),
+        created_at: now,
+    });
+    if let Some(mut order) = ORDER_STORAGE.with(|s| s.borrow().get(&order_id)) {
+        if order.set_status(OrderStatus::Refunded).is_ok() {
+            store_order(&order);
+            record_order_event(order.id, order.status, None);
+        }
+    }
+
+    Ok(refund)
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct ConfirmDeliveryPayload {
-    order_id: u64,
-    password: String,
-}
+// Define query functions to get the internal payment ledger of an Order
+#[ic_cdk::query]
+fn get_order_ledger(order_id: u64) -> Result<Vec<LedgerEntry>, Error> {
+    // Check if the order exists and the caller may see it
+    match ORDER_STORAGE.with(|s| s.borrow().get(&order_id)) {
+        Some(order) => require_order_party(&order)?,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("no order could be found for id: {}", order_id),
+            })
+        }
+    }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct DeleteReviewPayload {
-    review_id: u64,
-    password: String,
+    // Retrieve the entries of the order in the order they were recorded
+    let entries: Vec<LedgerEntry> = LEDGER_ENTRIES.with(|s| {
+        s.borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+
+    Ok(entries)
 }
 
-// Define query functions to get all Food Items
+// Define query functions to get a specific Courier by id
 #[ic_cdk::query]
-fn get_all_food_items() -> Result<Vec<Item>, Error> {
-    // Retrieve all items from the storage
-    let items_vec: Vec<(u64, Item)> = ITEM_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the items from the tuple and create a vector
-    let items: Vec<Item> = items_vec.into_iter().map(|(_, item)| item).collect();
+fn get_courier_by_id(id: u64) -> Result<Courier, Error> {
+    find_courier(id).map(courier_view)
+}
 
-    // Check if any items are found
-    match items.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("no Food items for order could be found"),
-        }),
-        _ => Ok(items),
+// Retrieve a courier from the storage
+fn find_courier(id: u64) -> Result<Courier, Error> {
+    COURIER_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("no courier could be found for id: {}", id),
+        })
+}
+
+// Hide where a courier is from callers other than the courier itself and admins
+fn courier_view(mut courier: Courier) -> Courier {
+    if caller_courier_id().ok() != Some(courier.id) && !has_role(env::caller(), Role::Admin) {
+        courier.location = None;
     }
+    courier
 }
 
-// Define query functions to get a specific Food Item by id
+// Define query functions to get a page of the Couriers that are online
 #[ic_cdk::query]
-fn get_food_item_by_id(id: u64) -> Result<Item, Error> {
-    // Retrieve the item from the storage
-    let item: Option<Item> = ITEM_STORAGE.with(|s| s.borrow().get(&id));
+fn get_available_couriers(page: Page) -> Result<PageResult<Courier>, Error> {
+    require_role(&[Role::RestaurantStaff])?;
 
-    // Check if the item is found
-    match item {
-        Some(item) => Ok(item),
-        None => Err(Error::NotFound {
-            msg: format!("no Food item could be found for id: {}", id),
-        }),
-    }
+    // Retrieve the online couriers after the cursor from the storage
+    let couriers: PageResult<Courier> = COURIER_STORAGE.with(|s| {
+        let couriers = s.borrow();
+        let entries = couriers
+            .range((page.lower_bound(), Bound::Unbounded))
+            .filter(|(_, courier)| courier.available);
+        collect_page(entries, &page)
+    });
+
+    Ok(couriers.map(courier_view))
 }
 
-// Define update functions to create a new Food item
+// Define update functions to register the caller as a Courier
 #[ic_cdk::update]
-fn create_food_item(payload: ItemPayload) -> Result<Item, Error> {
+fn register_courier(payload: CourierPayload) -> Result<Courier, Error> {
+    require_role(&[Role::Courier])?;
+
     // Validate the payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Check if the caller is already registered
+    let caller = env::caller();
+    if let Ok(courier_id) = caller_courier_id() {
         return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
+            msg: format!("caller is already registered as courier id: {}", courier_id),
         });
     }
 
@@ -266,233 +4661,480 @@ fn create_food_item(payload: ItemPayload) -> Result<Item, Error> {
         })
         .expect("Cannot increment Ids");
 
-    // Create a new Food item
-    let item: Item = Item {
+    // Create a new Courier, offline until they go online
+    let courier: Courier = Courier {
         id,
+        principal: caller,
         name: payload.name,
-        description: payload.description,
-        price: payload.price,
-        category: payload.category,
+        vehicle: payload.vehicle,
+        available: false,
+        location: None,
     };
 
-    // Store the new Food item in the storage
-    ITEM_STORAGE.with(|s| s.borrow_mut().insert(id, item.clone()));
+    // Store the new Courier in the storage and bind it to the caller
+    COURIER_STORAGE.with(|s| s.borrow_mut().insert(id, courier.clone()));
+    COURIER_INDEX.with(|s| s.borrow_mut().insert(StorablePrincipal(caller), id));
 
-    // Return the new Food item
-    Ok(item)
+    Ok(courier)
 }
 
-// Define query functions to delete a specific Food Item by id
+// Define update functions for the calling Courier to go online
 #[ic_cdk::update]
-fn delete_food_item_by_id(id: u64) -> Result<String, Error> {
-    //    check if the item is exists
-    match ITEM_STORAGE.with(|s| s.borrow().get(&id)) {
-        Some(_) => (),
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("Food item id: {} could not be found", id),
-            })
-        }
+fn go_online(location: Option<Location>) -> Result<Courier, Error> {
+    // Validate the location
+    if let Some(Err(e)) = location.map(|l| l.validate()) {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
     }
 
-    // delete item reviews
-    let reviews_vec: Vec<(u64, Review)> = REVIEW_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the reviews from the tuple and create a vector
-    let reviews: Vec<Review> = reviews_vec.into_iter().map(|(_, review)| review).collect();
+    let mut courier = find_courier(caller_courier_id()?)?;
+    courier.available = true;
+    if location.is_some() {
+        courier.location = location;
+    }
+    COURIER_STORAGE.with(|s| s.borrow_mut().insert(courier.id, courier.clone()));
 
-    // Filter the reviews by item_id
-    let reviews_by_item_id: Vec<Review> = reviews
-        .into_iter()
-        .filter(|review| review.item_id == id)
-        .collect();
+    Ok(courier)
+}
 
-    // Check if any reviews are found
-    match reviews_by_item_id.len() {
-        0 => (),
+// Define update functions for the calling Courier to go offline
+#[ic_cdk::update]
+fn go_offline() -> Result<Courier, Error> {
+    let mut courier = find_courier(caller_courier_id()?)?;
+    courier.available = false;
+    COURIER_STORAGE.with(|s| s.borrow_mut().insert(courier.id, courier.clone()));
+
+    Ok(courier)
+}
+
+// Define update functions to assign a Courier to an Order
+#[ic_cdk::update]
+fn assign_courier(order_id: u64, courier_id: u64) -> Result<Order, Error> {
+    require_role(&[Role::RestaurantStaff])?;
+
+    // Retrieve the order and the courier from the storage
+    let mut order = ORDER_STORAGE
+        .with(|s| s.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!("no order could be found for id: {}", order_id),
+        })?;
+    require_owner_of_restaurant(order.restaurant_id)?;
+    let courier = find_courier(courier_id)?;
+
+    // Check if the order still waits for pickup and the courier is online
+    if !matches!(
+        order.status,
+        OrderStatus::Placed
+            | OrderStatus::Paid
+            | OrderStatus::Accepted
+            | OrderStatus::Preparing
+            | OrderStatus::ReadyForPickup
+    ) {
+        return Err(Error::InvalidTransition {
+            msg: format!(
+                "order id: {} cannot be assigned while {:?}",
+                order.id, order.status
+            ),
+        });
+    }
+    if !courier.available {
+        return Err(Error::InvalidPayload {
+            msg: format!("courier id: {} is offline", courier.id),
+        });
+    }
+
+    // Assign the courier, replacing any previous assignment
+    order.assignment = Some(Assignment {
+        courier_id,
+        accepted: false,
+        assigned_at: env::time(),
+    });
+    store_order(&order);
+    record_order_event(
+        order.id,
+        order.status,
+        Some(format!("courier id: {} assigned", courier_id)),
+    );
+    refresh_order_eta(&order);
+
+    Ok(order)
+}
+
+// Accept or decline a pending assignment on behalf of the calling courier
+fn respond_to_assignment(order_id: u64, accept: bool) -> Result<Order, Error> {
+    let courier_id = caller_courier_id()?;
+
+    // Retrieve the order from the storage
+    let mut order = ORDER_STORAGE
+        .with(|s| s.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!("no order could be found for id: {}", order_id),
+        })?;
+
+    // Check if the order is waiting on this courier
+    match &order.assignment {
+        Some(assignment) if assignment.courier_id == courier_id && !assignment.accepted => (),
         _ => {
-            for review in reviews_by_item_id {
-                REVIEW_STORAGE.with(|s| s.borrow_mut().remove(&review.id));
-            }
+            return Err(Error::Forbidden {
+                msg: format!(
+                    "order id: {} has no pending assignment for courier id: {}",
+                    order.id, courier_id
+                ),
+            })
         }
     }
 
-    // Delete the Food item from the storage
-    match ITEM_STORAGE.with(|s| s.borrow_mut().remove(&id)) {
-        Some(_) => Ok(format!("Food item id: {} deleted", id)),
-        None => Err(Error::NotFound {
-            msg: format!("Food item id: {} could not be deleted", id),
-        }),
-    }
+    // Accept keeps the courier on the order, decline frees it for reassignment
+    let note = if accept {
+        order.assignment = order.assignment.map(|a| Assignment {
+            accepted: true,
+            ..a
+        });
+        format!("courier id: {} accepted", courier_id)
+    } else {
+        order.assignment = None;
+        format!("courier id: {} declined", courier_id)
+    };
+    store_order(&order);
+    record_order_event(order.id, order.status, Some(note));
+    refresh_order_eta(&order);
+
+    Ok(order)
 }
 
-//  get food items by category
-#[ic_cdk::query]
-fn get_food_items_by_category(category: String) -> Result<Vec<Item>, Error> {
-    // Retrieve all items from the storage
-    let items_vec: Vec<(u64, Item)> = ITEM_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the items from the tuple and create a vector
-    let items: Vec<Item> = items_vec.into_iter().map(|(_, item)| item).collect();
+// Define update functions for the assigned Courier to accept an Order
+#[ic_cdk::update]
+fn accept_assignment(order_id: u64) -> Result<Order, Error> {
+    respond_to_assignment(order_id, true)
+}
 
-    // Filter the items by category
-    let items_by_category: Vec<Item> = items
-        .into_iter()
-        .filter(|item| (item.category).contains(&category) || (item.description).contains(&category))
-        .collect();
+// Define update functions for the assigned Courier to decline an Order
+#[ic_cdk::update]
+fn decline_assignment(order_id: u64) -> Result<Order, Error> {
+    respond_to_assignment(order_id, false)
+}
 
-    // Check if any items are found
-    match items_by_category.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("no Food items for category: {} could be found", category),
-        }),
-        _ => Ok(items_by_category),
+// Define query functions to get a page of the Orders assigned to a specific Courier
+#[ic_cdk::query]
+fn get_orders_for_courier(courier_id: u64, page: Page) -> Result<PageResult<Order>, Error> {
+    // Only the courier itself or dispatchers may list its orders
+    if caller_courier_id().ok() != Some(courier_id) {
+        require_role(&[Role::RestaurantStaff])?;
     }
+
+    // Retrieve the orders assigned to the courier through the index
+    let orders: PageResult<Order> = COURIER_ORDER_INDEX.with(|s| {
+        index_page(&s.borrow(), courier_id, &page, |id| {
+            ORDER_STORAGE.with(|s| s.borrow().get(&id))
+        })
+    });
+
+    Ok(orders)
 }
 
-// Define query functions to get all Orders
-#[ic_cdk::query]
-fn get_all_orders() -> Result<Vec<Order>, Error> {
-    // Retrieve all orders from the storage
-    let orders_vec: Vec<(u64, Order)> = ORDER_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the orders from the tuple and create a vector
-    let orders: Vec<Order> = orders_vec.into_iter().map(|(_, order)| order).collect();
+// Define update functions for the assigned Courier to report its location on an Order
+#[ic_cdk::update]
+fn report_location(
+    order_id: u64,
+    latitude: f64,
+    longitude: f64,
+    accuracy: f64,
+) -> Result<LocationPoint, Error> {
+    // Validate the location
+    let location = Location {
+        latitude,
+        longitude,
+    };
+    if let Err(e) = location.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    if !(accuracy >= 0.0 && accuracy.is_finite()) {
+        return Err(Error::InvalidPayload {
+            msg: "accuracy must be a non-negative number of meters".to_string(),
+        });
+    }
 
-    // Check if any orders are found
-    match orders.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("no orders could be found"),
-        }),
-        _ => Ok(orders),
+    // Retrieve the order from the storage
+    let order = ORDER_STORAGE
+        .with(|s| s.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!("no order could be found for id: {}", order_id),
+        })?;
+    require_assigned_courier(&order)?;
+
+    // Check if the order is still being delivered
+    if order.status.is_final() {
+        return Err(Error::InvalidPayload {
+            msg: format!("order id: {} is no longer active", order.id),
+        });
     }
+
+    let point = LocationPoint {
+        latitude,
+        longitude,
+        accuracy,
+        timestamp: env::time(),
+    };
+
+    // Append the point to the route, dropping the oldest point once the ring is full
+    ORDER_LOCATIONS.with(|s| {
+        let mut points = s.borrow_mut();
+        let seq = points
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .last()
+            .map_or(0, |((_, seq), _)| seq + 1);
+        points.insert((order_id, seq), point.clone());
+        if seq >= MAX_ROUTE_POINTS {
+            points.remove(&(order_id, seq - MAX_ROUTE_POINTS));
+        }
+    });
+
+    // Keep the order's ETA up to date; the point stays on the route of the order, which only
+    // its participants can read, and is not copied onto the courier
+    refresh_order_eta(&order);
+
+    Ok(point)
 }
 
-// Define query functions to get a specific Order by id
+// Define query functions to get the latest location of an Order
 #[ic_cdk::query]
-fn get_order_by_id(id: u64) -> Result<Order, Error> {
+fn get_order_location(order_id: u64) -> Result<LocationPoint, Error> {
+    get_order_route(order_id)?.pop().ok_or(Error::NotFound {
+        msg: format!("no location has been reported for order id: {}", order_id),
+    })
+}
+
+// Define query functions to get the full route of an Order
+#[ic_cdk::query]
+fn get_order_route(order_id: u64) -> Result<Vec<LocationPoint>, Error> {
     // Retrieve the order from the storage
-    let order: Option<Order> = ORDER_STORAGE.with(|s| s.borrow().get(&id));
+    let order = ORDER_STORAGE
+        .with(|s| s.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!("no order could be found for id: {}", order_id),
+        })?;
+    require_order_participant(&order)?;
 
-    // Check if the order is found
-    match order {
-        Some(order) => Ok(order),
-        None => Err(Error::NotFound {
-            msg: format!("no order could be found for id: {}", id),
-        }),
-    }
+    // Retrieve the points of the order in the order they were reported
+    let points: Vec<LocationPoint> = ORDER_LOCATIONS.with(|s| {
+        s.borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .map(|(_, point)| point)
+            .collect()
+    });
+
+    Ok(points)
 }
 
-// Define query functions to get all Orders for a specific Client
+// Define query functions to get the estimated arrival of an Order
 #[ic_cdk::query]
-fn get_orders_by_client_id(client_id: u64) -> Result<Vec<Order>, Error> {
-    // Retrieve all orders from the storage
-    let orders_vec: Vec<(u64, Order)> = ORDER_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the orders from the tuple and create a vector
-    let orders: Vec<Order> = orders_vec.into_iter().map(|(_, order)| order).collect();
+fn get_order_eta(order_id: u64) -> Result<OrderEta, Error> {
+    // Retrieve the order from the storage
+    let order = ORDER_STORAGE
+        .with(|s| s.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!("no order could be found for id: {}", order_id),
+        })?;
+    require_order_participant(&order)?;
 
-    // Filter the orders by client_id
-    let orders_by_client_id: Vec<Order> = orders
-        .into_iter()
-        .filter(|order| order.client_id == client_id)
-        .collect();
+    ORDER_ETAS
+        .with(|s| s.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "no arrival estimate is available for order id: {}",
+                order_id
+            ),
+        })
+}
 
-    // add item quantity to Item
-    // Check if any orders are found
-    match orders_by_client_id.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("no orders could be found for client_id: {}", client_id),
-        }),
-        _ => Ok(orders_by_client_id),
-    }
+// Define a struct for a checked cart, priced and ready to be placed as an 'Order'
+struct PricedCart {
+    lines: Vec<OrderLine>,
+    restaurant_id: Option<u64>,
+    price_breakdown: PriceBreakdown,
+    promotion_id: Option<u64>,
 }
 
-// Define query functions to confirm order delivery
-#[ic_cdk::update]
-fn confirm_delivery(payload: ConfirmDeliveryPayload) -> Result<String, Error> {
-    // Retrieve the order from the storage
-    let order: Option<Order> = ORDER_STORAGE.with(|s| s.borrow().get(&payload.order_id));
+// Check a cart and price it with the current menu and pricing rules, without changing any state
+fn price_cart(payload: &OrderPayload) -> Result<PricedCart, Error> {
+    // Validate the delivery location
+    if let Some(Err(e)) = payload.delivery_location.map(|l| l.validate()) {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    if let Some(tip) = &payload.tip {
+        require_base_currency(tip, "tip")?;
+    }
 
-    // Check if the order is found
-    match order {
-        Some(order) => {
-            // Retrieve the client from the storage
-            let client: Option<Client> = CLIENT_STORAGE.with(|s| s.borrow().get(&order.client_id));
-
-            // Check if the client is found
-            match client {
-                Some(client) => {
-                    // Check if the password matches
-                    if client.password == payload.password {
-                        // Check if the order is already delivered
-                        if order.delivered {
-                            return Err(Error::AlreadyDelivered {
-                                msg: format!("order id: {} is already delivered", order.id),
+    // Check the cart line by line, collecting every problem before rejecting it
+    let max_quantity = CONFIG.with(|c| c.borrow().get().max_item_quantity);
+    let mut problems: Vec<OrderProblem> = Vec::new();
+    if !CLIENT_STORAGE.with(|s| s.borrow().contains_key(&payload.client_id)) {
+        problems.push(OrderProblem::MissingClient {
+            client_id: payload.client_id,
+        });
+    }
+    if payload.items.is_empty() {
+        problems.push(OrderProblem::EmptyCart);
+    }
+    let distinct_items = payload
+        .items
+        .iter()
+        .map(|order_item| order_item.item_id)
+        .collect::<HashSet<u64>>()
+        .len();
+    if distinct_items > MAX_ORDER_LINES {
+        problems.push(OrderProblem::TooManyLines {
+            lines: distinct_items as u64,
+            max: MAX_ORDER_LINES as u64,
+        });
+    }
+
+    // add quantities of items with the same item Id
+    let mut payload_items: HashMap<u64, u64> = HashMap::new();
+    let mut order_items: Vec<Item> = Vec::new();
+    for (line, order_item) in payload.items.iter().enumerate() {
+        let line = line as u64;
+        let item_id = order_item.item_id;
+        if order_item.quantity == 0 {
+            problems.push(OrderProblem::ZeroQuantity { line, item_id });
+            continue;
+        }
+        if !payload_items.contains_key(&item_id) {
+            match ITEM_STORAGE.with(|s| s.borrow().get(&item_id)) {
+                Some(item) if item.is_orderable() => {
+                    // An order is paid in one currency, set by its first item
+                    if let Some(first) = order_items.first() {
+                        if item.price.currency != first.price.currency {
+                            problems.push(OrderProblem::MixedCurrencies {
+                                line,
+                                item_id,
+                                currency: item.price.currency,
                             });
+                            continue;
                         }
-
-                        // Update the order status
-                        ORDER_STORAGE.with(|s| {
-                            s.borrow_mut().insert(
-                                order.id,
-                                Order {
-                                    delivered: true,
-                                    status: "order delivered".to_string(),
-                                    ..order
-                                },
-                            )
-                        });
-
-                        Ok(format!("order id: {} is delivered", order.id))
-                    } else {
-                        Err(Error::Unauthorized {
-                            msg: format!("password is incorrect"),
-                        })
                     }
+                    order_items.push(item)
+                }
+                Some(_) => {
+                    problems.push(OrderProblem::ItemUnavailable { line, item_id });
+                    continue;
+                }
+                None => {
+                    problems.push(OrderProblem::UnknownItem { line, item_id });
+                    continue;
                 }
-                None => Err(Error::NotFound {
-                    msg: format!("no client could be found for id: {}", order.client_id),
-                }),
             }
         }
-        None => Err(Error::NotFound {
-            msg: format!("no order could be found for id: {}", payload.order_id),
-        }),
+
+        // Report the line that pushes the item over the cap
+        let quantity = payload_items.entry(item_id).or_insert(0);
+        let previous = *quantity;
+        *quantity = quantity.saturating_add(order_item.quantity);
+        if previous <= max_quantity && *quantity > max_quantity {
+            problems.push(OrderProblem::QuantityAboveLimit {
+                line,
+                item_id,
+                quantity: *quantity,
+                max: max_quantity,
+            });
+        }
+    }
+    if !problems.is_empty() {
+        return Err(Error::InvalidOrder {
+            msg: format!(
+                "order could not be placed: {} problem(s) found",
+                problems.len()
+            ),
+            problems,
+        });
     }
-}
 
-// Define update functions to update order status
-#[ic_cdk::update]
-fn update_order_status(order_id: u64, status: String) -> Result<String, Error> {
-    // Retrieve the order from the storage
-    let order: Option<Order> = ORDER_STORAGE.with(|s| s.borrow().get(&order_id));
+    // Check that all items are sold by the same restaurant
+    let mut restaurant_ids: Vec<Option<u64>> =
+        order_items.iter().map(|item| item.restaurant_id).collect();
+    restaurant_ids.sort();
+    restaurant_ids.dedup();
+    if restaurant_ids.len() > 1 {
+        return Err(Error::InvalidPayload {
+            msg: "all items of an order must come from the same restaurant".to_string(),
+        });
+    }
+    let restaurant_id = restaurant_ids.first().copied().flatten();
 
-    // Check if the order is found
-    match order {
-        Some(order) => {
-            // Update the order status
-            ORDER_STORAGE.with(|s| {
-                s.borrow_mut().insert(
-                    order.id,
-                    Order {
-                        status: status.clone(),
-                        ..order
-                    },
-                )
-            });
+    // Check that the restaurant is accepting orders
+    let restaurant_location = match restaurant_id {
+        Some(restaurant_id) => match RESTAURANT_STORAGE.with(|s| s.borrow().get(&restaurant_id)) {
+            Some(restaurant) if restaurant.active => Some(Location {
+                latitude: restaurant.latitude,
+                longitude: restaurant.longitude,
+            }),
+            Some(_) => {
+                return Err(Error::InvalidPayload {
+                    msg: format!("restaurant id: {} is not accepting orders", restaurant_id),
+                })
+            }
+            None => {
+                return Err(Error::NotFound {
+                    msg: format!("no restaurant could be found for id: {}", restaurant_id),
+                })
+            }
+        },
+        None => None,
+    };
 
-            Ok(format!(
-                "order id: {} status updated to {}",
-                order.id, status
-            ))
-        }
-        None => Err(Error::NotFound {
-            msg: format!("no order could be found for id: {}", order_id),
-        }),
+    // Snapshot every item at its current price
+    let mut lines: Vec<OrderLine> = Vec::new();
+    for item in &order_items {
+        let line =
+            OrderLine::snapshot(item, payload_items[&item.id]).ok_or_else(|| Error::Overflow {
+                msg: format!("order total overflows at item id: {}", item.id),
+            })?;
+        lines.push(line);
     }
+
+    // Check that the promo code can be redeemed for this order
+    let promotion = match payload.promo_code.as_deref() {
+        Some(code) => Some(redeemable_promotion(
+            code,
+            payload.client_id,
+            restaurant_id,
+        )?),
+        None => None,
+    };
+
+    // Add up the lines, fees, tax and tip, less the discount
+    let delivery_distance_km = restaurant_location
+        .zip(payload.delivery_location)
+        .map(|(from, to)| from.distance_km(&to));
+    let price_breakdown = price_lines(
+        &order_items,
+        &lines,
+        delivery_distance_km,
+        payload.tip,
+        promotion.as_ref(),
+    )?;
+
+    Ok(PricedCart {
+        lines,
+        restaurant_id,
+        price_breakdown,
+        promotion_id: promotion.map(|promotion| promotion.id),
+    })
+}
+
+// Define query functions to preview the itemised price of an Order before placing it
+#[ic_cdk::query]
+fn quote_order(payload: OrderPayload) -> Result<PriceBreakdown, Error> {
+    require_owner_or_role(payload.client_id, &[Role::Admin])?;
+    price_cart(&payload).map(|cart| cart.price_breakdown)
 }
 
 // Define update functions to create a new Order
 #[ic_cdk::update]
 fn create_order(payload: OrderPayload) -> Result<Order, Error> {
-    // Retrieve the next id from the storage
+    require_owner_or_role(payload.client_id, &[Role::Admin])?;
+    let cart = price_cart(&payload)?;
+
+    // Retrieve the next id from the storage now that the order is valid
     let id = ID_COUNTER
         .with(|counter| {
             let current_id = *counter.borrow().get();
@@ -500,76 +5142,82 @@ fn create_order(payload: OrderPayload) -> Result<Order, Error> {
         })
         .expect("Cannot increment Ids");
 
-    // add quantities of items with the same item Id
-    let mut payload_items: HashMap<u64, u64> = HashMap::new();
-    for item in payload.items {
-        *payload_items.entry(item.item_id).or_insert(0) += item.quantity;
-    }
-
-    let items_vec: Vec<(u64, Item)> = ITEM_STORAGE.with(|s| s.borrow().iter().collect());
-    let items: Vec<Item> = items_vec.into_iter().map(|(_, item)| item).collect();
-    // get order items from payload
-    let order_items: Vec<Item> = items
-        .into_iter()
-        .filter(|item| payload_items.contains_key(&item.id))
-        .collect();
-
-    // calculate total price by multiplying item price by quantity
-    let total: u64 = order_items
-        .iter()
-        .map(|item| {
-            let item = ITEM_STORAGE.with(|s| s.borrow().get(&item.id).unwrap());
-            let quantity = payload_items.get(&item.id).unwrap();
-            item.price * quantity
-        })
-        .sum();
-
     // Create a new Order
     let order: Order = Order {
         id,
         client_id: payload.client_id,
-        items: payload_items,
-        total,
-        status: "order placed".to_string(),
+        lines: cart.lines,
+        total: cart.price_breakdown.total,
+        status: OrderStatus::Placed,
         delivered: false,
+        restaurant_id: cart.restaurant_id,
+        assignment: None,
+        delivery_location: payload.delivery_location,
+        cancellation: None,
+        amount_captured: None,
+        price_breakdown: Some(cart.price_breakdown),
+        promotion_id: cart.promotion_id,
     };
 
-    // Store the new Order in the storage
-    ORDER_STORAGE.with(|s| s.borrow_mut().insert(id, order.clone()));
+    // Store the new Order in the storage and link it to the client
+    store_order(&order);
+    count_ordered_items(&order);
+    if let Some(promotion_id) = order.promotion_id {
+        update_redemptions(promotion_id, order.client_id, true);
+    }
+    CLIENT_ORDER_INDEX.with(|s| s.borrow_mut().insert((order.client_id, id), ()));
+    CLIENT_STORAGE.with(|s| {
+        let client = s.borrow().get(&order.client_id);
+        if let Some(mut client) = client {
+            client.order_ids.insert(0, id);
+            client.order_ids.truncate(MAX_CLIENT_ORDER_IDS);
+            s.borrow_mut().insert(client.id, client);
+        }
+    });
+    record_order_event(id, order.status, None);
+    refresh_order_eta(&order);
 
     // Return the new Order
     Ok(order)
 }
 
-// Define query functions to get all Reviews
+// Define query functions to get a page of Reviews
+// Deprecated: use 'get_all_reviews_v2', which returns an empty page instead of 'NotFound'
 #[ic_cdk::query]
-fn get_all_reviews() -> Result<Vec<Review>, Error> {
-    // Retrieve all reviews from the storage
-    let reviews_vec: Vec<(u64, Review)> = REVIEW_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the reviews from the tuple and create a vector
-    let reviews: Vec<Review> = reviews_vec.into_iter().map(|(_, review)| review).collect();
+fn get_all_reviews(page: Page) -> Result<PageResult<Review>, Error> {
+    // Retrieve the requested slice of reviews from the storage
+    let reviews: PageResult<Review> = REVIEW_STORAGE.with(|s| page_of(&s.borrow(), &page));
 
     // Check if any reviews are found
-    match reviews.len() {
+    match reviews.items.len() {
         0 => Err(Error::NotFound {
-            msg: format!("no reviews could be found"),
+            msg: "no reviews could be found".to_string(),
         }),
         _ => Ok(reviews),
     }
 }
 
+// Define query functions to get a page of Reviews with the total number of reviews
+#[ic_cdk::query]
+fn get_all_reviews_v2(page: Page) -> Result<ListResult<Review>, Error> {
+    // Retrieve the requested slice of reviews from the storage
+    let reviews: ListResult<Review> = REVIEW_STORAGE.with(|s| {
+        let storage = s.borrow();
+        page_of(&storage, &page).with_total(storage.len())
+    });
+
+    Ok(reviews)
+}
+
 // Define query functions to get all Reviews for a specific Item
+// Deprecated: use 'get_reviews_by_item_id_v2', which returns an empty page instead of 'NotFound'
 #[ic_cdk::query]
 fn get_reviews_by_item_id(item_id: u64) -> Result<Vec<Review>, Error> {
-    // Retrieve all reviews from the storage
-    let reviews_vec: Vec<(u64, Review)> = REVIEW_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the reviews from the tuple and create a vector
-    let reviews: Vec<Review> = reviews_vec.into_iter().map(|(_, review)| review).collect();
-
-    // Filter the reviews by item_id
-    let reviews_by_item_id: Vec<Review> = reviews
+    // Retrieve the reviews of the item through the index
+    let reviews_by_item_id: Vec<Review> = ITEM_REVIEW_INDEX
+        .with(|s| index_ids(&s.borrow(), item_id))
         .into_iter()
-        .filter(|review| review.item_id == item_id)
+        .filter_map(|id| REVIEW_STORAGE.with(|s| s.borrow().get(&id)))
         .collect();
 
     // Check if any reviews are found
@@ -581,9 +5229,94 @@ fn get_reviews_by_item_id(item_id: u64) -> Result<Vec<Review>, Error> {
     }
 }
 
-// Define update functions to create a new Review
+// Define query functions to get a page of Reviews for a specific Item with the total number
+// of reviews of the item
+#[ic_cdk::query]
+fn get_reviews_by_item_id_v2(item_id: u64, page: Page) -> Result<ListResult<Review>, Error> {
+    // Count the reviews from the rating totals kept on the item
+    let total = ITEM_STORAGE
+        .with(|s| s.borrow().get(&item_id))
+        .and_then(|item| item.rating)
+        .map_or(0, |stats| stats.count);
+
+    // Retrieve the reviews of the item through the index
+    let reviews: ListResult<Review> = ITEM_REVIEW_INDEX.with(|s| {
+        let index = s.borrow();
+        index_page(&index, item_id, &page, |id| {
+            REVIEW_STORAGE.with(|s| s.borrow().get(&id))
+        })
+        .with_total(total)
+    });
+
+    Ok(reviews)
+}
+
+// Define query functions to get the rating statistics of a specific Item
+#[ic_cdk::query]
+fn get_rating_summary(item_id: u64) -> Result<RatingSummary, Error> {
+    match ITEM_STORAGE.with(|s| s.borrow().get(&item_id)) {
+        Some(item) => Ok(RatingSummary::new(item_id, item.rating.unwrap_or_default())),
+        None => Err(Error::NotFound {
+            msg: format!("no Food item could be found for id: {}", item_id),
+        }),
+    }
+}
+
+// Define update functions to create a new Review for an Item of a delivered Order of the caller
 #[ic_cdk::update]
 fn create_review(payload: ReviewPayload) -> Result<Review, Error> {
+    require_role(&[Role::Customer])?;
+
+    // Validate the payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Resolve the client bound to the caller
+    let client_id = caller_client_id()?;
+
+    // Check that the caller bought the item in a delivered order
+    let order = match ORDER_STORAGE.with(|s| s.borrow().get(&payload.order_id)) {
+        Some(order) if order.client_id == client_id => order,
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!(
+                    "no order could be found for id: {} and the caller",
+                    payload.order_id
+                ),
+            })
+        }
+    };
+    if !order
+        .lines
+        .iter()
+        .any(|line| line.item_id == payload.item_id)
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "order id: {} does not contain item id: {}",
+                order.id, payload.item_id
+            ),
+        });
+    }
+    if !order_was_delivered(&order) {
+        return Err(Error::InvalidPayload {
+            msg: format!("order id: {} has not been delivered", order.id),
+        });
+    }
+
+    // Check that the item has not been reviewed for this order yet
+    if let Some(review_id) =
+        ORDER_ITEM_REVIEW_INDEX.with(|s| s.borrow().get(&(order.id, payload.item_id)))
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "item id: {} of order id: {} is already reviewed in review id: {}",
+                payload.item_id, order.id, review_id
+            ),
+        });
+    }
+
     // Retrieve the next id from the storage
     let id = ID_COUNTER
         .with(|counter| {
@@ -595,14 +5328,15 @@ fn create_review(payload: ReviewPayload) -> Result<Review, Error> {
     // Create a new Review
     let review: Review = Review {
         id,
-        client_id: 0,
+        client_id: Some(client_id),
         item_id: payload.item_id,
         rating: payload.rating,
         comment: payload.comment,
+        order_id: Some(order.id),
     };
 
     // Store the new Review in the storage
-    REVIEW_STORAGE.with(|s| s.borrow_mut().insert(id, review.clone()));
+    store_review(&review);
 
     // Return the new Review
     Ok(review)
@@ -611,35 +5345,29 @@ fn create_review(payload: ReviewPayload) -> Result<Review, Error> {
 // Define query functions to delete a specific Review by id
 #[ic_cdk::update]
 fn delete_review_by_id(payload: DeleteReviewPayload) -> Result<String, Error> {
+    // Resolve the client bound to the caller, if any
+    let client_id = caller_client_id().ok();
+
     // Retrieve the review from the storage
     let review: Option<Review> = REVIEW_STORAGE.with(|s| s.borrow().get(&payload.review_id));
 
     // Check if the review is found
     match review {
         Some(review) => {
-            // Retrieve the client from the storage
-            let client: Option<Client> = CLIENT_STORAGE.with(|s| s.borrow().get(&review.client_id));
-
-            // Check if the client is found
-            match client {
-                Some(client) => {
-                    // Check if the password matches
-                    if client.password == payload.password {
-                        // Delete the Review from the storage
-                        match REVIEW_STORAGE.with(|s| s.borrow_mut().remove(&review.id)) {
-                            Some(_) => Ok(format!("Review id: {} deleted", review.id)),
-                            None => Err(Error::NotFound {
-                                msg: format!("Review id: {} could not be deleted", review.id),
-                            }),
-                        }
-                    } else {
-                        Err(Error::Unauthorized {
-                            msg: format!("password is incorrect"),
-                        })
-                    }
-                }
+            // Check if the review was written by the caller; reviews without an author are
+            // left to admins
+            let written_by_caller = client_id.is_some() && client_id == review.client_id;
+            if !written_by_caller && !has_role(env::caller(), Role::Admin) {
+                return Err(Error::Unauthorized {
+                    msg: format!("review id: {} does not belong to the caller", review.id),
+                });
+            }
+
+            // Delete the Review from the storage
+            match remove_review(review.id) {
+                Some(_) => Ok(format!("Review id: {} deleted", review.id)),
                 None => Err(Error::NotFound {
-                    msg: format!("no client could be found for id: {}", review.client_id),
+                    msg: format!("Review id: {} could not be deleted", review.id),
                 }),
             }
         }
@@ -649,76 +5377,76 @@ fn delete_review_by_id(payload: DeleteReviewPayload) -> Result<String, Error> {
     }
 }
 
-// Define query functions to get all Clients
+// Define query functions to get a page of Clients
+// Deprecated: use 'get_all_clients_v2', which returns an empty page instead of 'NotFound'
 #[ic_cdk::query]
-fn get_all_clients() -> Result<Vec<ClientResponse>, Error> {
-    // Retrieve all clients from the storage
-    let clients_vec: Vec<(u64, Client)> = CLIENT_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the clients from the tuple and create a vector
-    let clients: Vec<Client> = clients_vec.into_iter().map(|(_, client)| client).collect();
+fn get_all_clients(page: Page) -> Result<PageResult<ClientResponse>, Error> {
+    require_role(&[Role::Admin])?;
+
+    // Retrieve the requested slice of clients from the storage
+    let clients: PageResult<Client> = CLIENT_STORAGE.with(|s| page_of(&s.borrow(), &page));
 
     // Check if any clients are found
-    match clients.len() {
+    match clients.items.len() {
         0 => Err(Error::NotFound {
-            msg: format!("no clients could be found"),
+            msg: "no clients could be found".to_string(),
         }),
-        _ => {
-            // Create a vector of ClientResponse structs
-            let mut client_responses: Vec<ClientResponse> = Vec::new();
-
-            // Iterate over the clients and create a ClientResponse struct for each client
-            for client in clients {
-                let client_response: ClientResponse = ClientResponse {
-                    id: client.id,
-                    name: client.name,
-                    address: client.address,
-                    phone: client.phone,
-                    email: client.email,
-                    order_ids: client.order_ids,
-                };
-                client_responses.push(client_response);
-            }
-
-            Ok(client_responses)
-        }
+        // Create a ClientResponse struct for each client
+        _ => Ok(clients.map(ClientResponse::from)),
     }
 }
 
+// Define query functions to get a page of Clients with the total number of clients
+#[ic_cdk::query]
+fn get_all_clients_v2(page: Page) -> Result<ListResult<ClientResponse>, Error> {
+    require_role(&[Role::Admin])?;
+
+    // Retrieve the requested slice of clients from the storage
+    let clients: ListResult<ClientResponse> = CLIENT_STORAGE.with(|s| {
+        let storage = s.borrow();
+        page_of(&storage, &page)
+            .map(ClientResponse::from)
+            .with_total(storage.len())
+    });
+
+    Ok(clients)
+}
+
 // Define query functions to get a specific Client by id
 #[ic_cdk::query]
 fn get_client_by_id(id: u64) -> Result<ClientResponse, Error> {
+    require_owner_or_role(id, &[Role::Admin])?;
+
     // Retrieve the client from the storage
     let client: Option<Client> = CLIENT_STORAGE.with(|s| s.borrow().get(&id));
 
     // Check if the client is found
     match client {
-        Some(client) => {
-            // Create a ClientResponse struct
-            let client_response: ClientResponse = ClientResponse {
-                id: client.id,
-                name: client.name,
-                address: client.address,
-                phone: client.phone,
-                email: client.email,
-                order_ids: client.order_ids,
-            };
-
-            Ok(client_response)
-        }
+        Some(client) => Ok(ClientResponse::from(client)),
         None => Err(Error::NotFound {
             msg: format!("no client could be found for id: {}", id),
         }),
     }
 }
 
-// Define update functions to create a new Client
+// Define update functions to create a new Client bound to the caller
 #[ic_cdk::update]
-fn create_client(payload: ClientPayload) -> Result<Client, Error> {
+fn create_client(payload: ClientPayload) -> Result<ClientResponse, Error> {
     // Validate the payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Check if the caller can own a client record
+    let caller = env::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "anonymous principals cannot create a client".to_string(),
+        });
+    }
+    if let Some(client_id) = PRINCIPAL_INDEX.with(|s| s.borrow().get(&StorablePrincipal(caller))) {
         return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
+            msg: format!("caller is already registered as client id: {}", client_id),
         });
     }
 
@@ -737,23 +5465,609 @@ fn create_client(payload: ClientPayload) -> Result<Client, Error> {
         address: payload.address,
         phone: payload.phone,
         email: payload.email,
-        password: payload.password,
+        password: String::new(),
+        principal: Some(caller),
         order_ids: Vec::new(),
+        updated_at: Some(env::time()),
+        deleted_at: None,
+    };
+
+    // Store the new Client in the storage and bind it to the caller
+    CLIENT_STORAGE.with(|s| s.borrow_mut().insert(id, client.clone()));
+    PRINCIPAL_INDEX.with(|s| s.borrow_mut().insert(StorablePrincipal(caller), id));
+    add_role(caller, Role::Customer);
+
+    Ok(ClientResponse::from(client))
+}
+
+// Define update functions to change the details of a Client
+#[ic_cdk::update]
+fn update_client(id: u64, payload: ClientUpdate) -> Result<ClientResponse, Error> {
+    require_owner_or_role(id, &[Role::Admin])?;
+
+    // Validate the payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Retrieve the client from the storage
+    let mut client = match CLIENT_STORAGE.with(|s| s.borrow().get(&id)) {
+        Some(client) => client,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("no client could be found for id: {}", id),
+            })
+        }
     };
 
-    // Store the new Client in the storage
+    // Apply the fields that are set
+    if let Some(name) = payload.name {
+        client.name = name;
+    }
+    if let Some(address) = payload.address {
+        client.address = address;
+    }
+    if let Some(phone) = payload.phone {
+        client.phone = phone;
+    }
+    if let Some(email) = payload.email {
+        client.email = email;
+    }
+    client.updated_at = Some(env::time());
+
+    // Store the updated Client
     CLIENT_STORAGE.with(|s| s.borrow_mut().insert(id, client.clone()));
 
-    Ok(client)
+    Ok(ClientResponse::from(client))
+}
+
+// Define update functions to bind a password-based Client to the caller, wiping the password
+#[ic_cdk::update]
+fn claim_client(payload: ClaimClientPayload) -> Result<ClientResponse, Error> {
+    // Check if the caller can own a client record
+    let caller = env::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "anonymous principals cannot claim a client".to_string(),
+        });
+    }
+    if let Some(client_id) = PRINCIPAL_INDEX.with(|s| s.borrow().get(&StorablePrincipal(caller))) {
+        return Err(Error::InvalidPayload {
+            msg: format!("caller is already registered as client id: {}", client_id),
+        });
+    }
+
+    // Retrieve the client from the storage
+    let client: Option<Client> = CLIENT_STORAGE.with(|s| s.borrow().get(&payload.client_id));
+
+    // Check if the client is found
+    match client {
+        Some(mut client) => {
+            // Check if the client is still unclaimed and the password matches
+            if client.principal.is_some() || client.password.is_empty() {
+                return Err(Error::Unauthorized {
+                    msg: format!("client id: {} has already been claimed", client.id),
+                });
+            }
+            if client.password != payload.password {
+                return Err(Error::Unauthorized {
+                    msg: "password is incorrect".to_string(),
+                });
+            }
+
+            // Bind the client to the caller and wipe the password
+            client.principal = Some(caller);
+            client.password = String::new();
+            CLIENT_STORAGE.with(|s| s.borrow_mut().insert(client.id, client.clone()));
+            PRINCIPAL_INDEX.with(|s| s.borrow_mut().insert(StorablePrincipal(caller), client.id));
+            add_role(caller, Role::Customer);
+
+            Ok(ClientResponse::from(client))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("no client could be found for id: {}", payload.client_id),
+        }),
+    }
+}
+
+// Define query functions to export everything stored about the calling Client
+#[ic_cdk::query]
+fn export_my_data() -> Result<ClientDataExport, Error> {
+    caller_data_export()
+}
+
+// Define query functions to export everything stored about the calling Client as JSON
+#[ic_cdk::query]
+fn export_my_data_json() -> Result<String, Error> {
+    let export = caller_data_export()?;
+    serde_json::to_string(&export).map_err(|e| Error::InvalidPayload { msg: e.to_string() })
+}
+
+// Define update functions to delete the account of the calling Client, wiping its personal
+// data while keeping the financial records of its orders
+#[ic_cdk::update]
+fn delete_my_account() -> Result<String, Error> {
+    let caller = env::caller();
+    let client_id = caller_client_id()?;
+    let mut client =
+        CLIENT_STORAGE
+            .with(|s| s.borrow().get(&client_id))
+            .ok_or(Error::NotFound {
+                msg: format!("no client could be found for id: {}", client_id),
+            })?;
+
+    // Check that no order is still in progress or waiting on a refund, which is paid to the
+    // principal of the client
+    let orders = client_orders(client_id);
+    if let Some(order) = orders.iter().find(|order| !order.status.is_final()) {
+        return Err(Error::Forbidden {
+            msg: format!(
+                "order id: {} is still in progress; wait for it to finish or cancel it first",
+                order.id
+            ),
+        });
+    }
+    let refund_owed = |order: &Order| {
+        REFUND_STORAGE
+            .with(|s| s.borrow().get(&order.id))
+            .is_some_and(|refund| refund.settled_at.is_none())
+    };
+    if let Some(order) = orders.iter().find(|order| refund_owed(order)) {
+        return Err(Error::Forbidden {
+            msg: format!("order id: {} is still waiting on its refund", order.id),
+        });
+    }
+
+    // Drop the delivery locations and courier routes that lead to the client, and the caller's
+    // principal and notes from the timelines, cancellations and ledgers of its orders
+    for mut order in orders {
+        ORDER_LOCATIONS.with(|s| {
+            let keys: Vec<(u64, u64)> = s
+                .borrow()
+                .range((order.id, 0)..=(order.id, u64::MAX))
+                .map(|(key, _)| key)
+                .collect();
+            for key in keys {
+                s.borrow_mut().remove(&key);
+            }
+        });
+        ORDER_ETAS.with(|s| s.borrow_mut().remove(&order.id));
+        forget_principal_on_order(order.id, caller);
+        let mut changed = order.delivery_location.take().is_some();
+        if let Some(cancellation) = order.cancellation.as_mut() {
+            if cancellation.cancelled_by == caller {
+                cancellation.cancelled_by = Principal::anonymous();
+                cancellation.note = None;
+                changed = true;
+            }
+        }
+        if changed {
+            store_order(&order);
+        }
+    }
+
+    // Delete the reviews written by the client
+    for review in client_reviews(client_id) {
+        remove_review(review.id);
+    }
+
+    // Wipe the personal data and unbind the principal
+    let now = env::time();
+    client.name = "deleted client".to_string();
+    client.address = String::new();
+    client.phone = String::new();
+    client.email = String::new();
+    client.password = String::new();
+    client.principal = None;
+    client.updated_at = Some(now);
+    client.deleted_at = Some(now);
+    CLIENT_STORAGE.with(|s| s.borrow_mut().insert(client_id, client));
+    PRINCIPAL_INDEX.with(|s| s.borrow_mut().remove(&StorablePrincipal(caller)));
+    remove_role(caller, Role::Customer);
+
+    Ok(format!("client id: {} deleted", client_id))
+}
+
+// Define update functions to grant a role to a principal
+#[ic_cdk::update]
+fn grant_role(principal: Principal, role: Role) -> Result<String, Error> {
+    require_role(&[Role::Admin])?;
+
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "roles cannot be granted to the anonymous principal".to_string(),
+        });
+    }
+
+    add_role(principal, role);
+    Ok(format!("principal: {} granted role {:?}", principal, role))
+}
+
+// Define update functions to revoke a role from a principal
+#[ic_cdk::update]
+fn revoke_role(principal: Principal, role: Role) -> Result<String, Error> {
+    require_role(&[Role::Admin])?;
+
+    // Check if the principal holds the role
+    if !has_role(principal, role) {
+        return Err(Error::NotFound {
+            msg: format!("principal: {} does not hold role {:?}", principal, role),
+        });
+    }
+
+    // Keep at least one admin so the canister can still be administered
+    if role == Role::Admin {
+        let admins = ROLE_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .filter(|(_, set)| set.roles.contains(&Role::Admin))
+                .count()
+        });
+        if admins <= 1 {
+            return Err(Error::Forbidden {
+                msg: "the last admin cannot be revoked".to_string(),
+            });
+        }
+    }
+
+    remove_role(principal, role);
+    Ok(format!("principal: {} revoked role {:?}", principal, role))
+}
+
+// Define query functions to get the roles of the caller
+#[ic_cdk::query]
+fn get_my_roles() -> Vec<Role> {
+    roles_of(env::caller())
+}
+
+// Define update functions to rebuild the client order index and the order ids of every Client
+#[ic_cdk::update]
+fn rebuild_client_order_index() -> Result<String, Error> {
+    require_role(&[Role::Admin])?;
+
+    let orders = rebuild_client_orders();
+
+    Ok(format!("client order index rebuilt from {} orders", orders))
+}
+
+// Define query functions to get the canister settings
+#[ic_cdk::query]
+fn get_config() -> Config {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// Define update functions to replace the canister settings
+#[ic_cdk::update]
+fn update_config(config: Config) -> Result<Config, Error> {
+    require_role(&[Role::Admin])?;
+
+    if config.max_item_quantity == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "max_item_quantity must be at least 1".to_string(),
+        });
+    }
+    if config.courier_payout_bps.is_some_and(|bps| bps > MAX_BPS) {
+        return Err(Error::InvalidPayload {
+            msg: format!("courier_payout_bps cannot be above {}", MAX_BPS),
+        });
+    }
+    // Stored amounts and the ledger depend on the base currency, so it cannot change
+    if config
+        .base_currency
+        .is_some_and(|currency| currency != base_currency())
+    {
+        return Err(Error::InvalidPayload {
+            msg: "base_currency cannot be changed".to_string(),
+        });
+    }
+    let config = Config {
+        base_currency: Some(base_currency()),
+        layout_version: CONFIG.with(|c| c.borrow().get().layout_version),
+        ..config
+    };
+
+    CONFIG
+        .with(|c| c.borrow_mut().set(config.clone()))
+        .expect("Cannot update the config");
+    Ok(config)
+}
+
+// Define query functions to get the rules used to price orders
+#[ic_cdk::query]
+fn get_pricing_rules() -> PricingRules {
+    PRICING_RULES.with(|r| r.borrow().get().clone())
+}
+
+// Define update functions to replace the rules used to price new orders
+#[ic_cdk::update]
+fn update_pricing_rules(rules: PricingRules) -> Result<PricingRules, Error> {
+    require_role(&[Role::Admin])?;
+    validate_pricing_rules(&rules)?;
+
+    PRICING_RULES
+        .with(|r| r.borrow_mut().set(rules.clone()))
+        .expect("Cannot update the pricing rules");
+    Ok(rules)
+}
+
+// Check the terms of a promotion that its fields cannot check on their own, and that its code
+// is not taken by another promotion
+fn check_promotion_terms(payload: &PromotionPayload, id: Option<u64>) -> Result<(), Error> {
+    let invalid = |msg: String| Err(Error::InvalidPayload { msg });
+    if let Discount::FixedAmount { amount } = payload.discount {
+        require_base_currency(&amount, "a fixed discount")?;
+    }
+    if let Some(min_subtotal) = &payload.min_subtotal {
+        require_base_currency(min_subtotal, "min_subtotal")?;
+    }
+    match payload.discount {
+        Discount::Percentage { bps } if bps == 0 || bps > MAX_BPS => {
+            return invalid(format!(
+                "a percentage discount must be between 1 and {} basis points",
+                MAX_BPS
+            ))
+        }
+        Discount::FixedAmount { amount } if amount.is_zero() => {
+            return invalid("a fixed discount must be above zero".to_string())
+        }
+        Discount::FixedAmount { amount }
+            if payload
+                .min_subtotal
+                .is_some_and(|min| min.currency != amount.currency) =>
+        {
+            return invalid("min_subtotal must use the currency of the discount".to_string())
+        }
+        _ => {}
+    }
+    if payload
+        .valid_until
+        .is_some_and(|until| until <= payload.valid_from)
+    {
+        return invalid("valid_until must be after valid_from".to_string());
+    }
+    let taken = PROMOTION_CODE_INDEX
+        .with(|s| s.borrow().get(&promo_code_key(&payload.code)))
+        .is_some_and(|other| Some(other) != id);
+    if taken {
+        return invalid(format!(
+            "promo code: {} is already in use",
+            payload.code.trim()
+        ));
+    }
+    Ok(())
+}
+
+// Define query functions to get a page of Promotions with the total number of promotions
+#[ic_cdk::query]
+fn get_all_promotions(page: Page) -> Result<ListResult<Promotion>, Error> {
+    require_role(&[Role::Admin])?;
+
+    // Retrieve the requested slice of promotions from the storage
+    let promotions: ListResult<Promotion> = PROMOTION_STORAGE.with(|s| {
+        let storage = s.borrow();
+        page_of(&storage, &page).with_total(storage.len())
+    });
+
+    Ok(promotions)
+}
+
+// Define query functions to get a specific Promotion by id
+#[ic_cdk::query]
+fn get_promotion(id: u64) -> Result<Promotion, Error> {
+    require_role(&[Role::Admin])?;
+
+    PROMOTION_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("no promotion could be found for id: {}", id),
+        })
+}
+
+// Define update functions to create a new Promotion
+#[ic_cdk::update]
+fn create_promotion(payload: PromotionPayload) -> Result<Promotion, Error> {
+    require_role(&[Role::Admin])?;
+
+    // Validate the payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    check_promotion_terms(&payload, None)?;
+
+    // Retrieve the next id from the storage
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    // Create a new Promotion
+    let now = env::time();
+    let promotion = Promotion {
+        id,
+        code: payload.code.trim().to_string(),
+        discount: payload.discount,
+        min_subtotal: payload.min_subtotal,
+        valid_from: payload.valid_from,
+        valid_until: payload.valid_until,
+        max_redemptions: payload.max_redemptions,
+        max_redemptions_per_client: payload.max_redemptions_per_client,
+        first_order_only: payload.first_order_only,
+        categories: payload.categories,
+        restaurant_ids: payload.restaurant_ids,
+        redemptions: 0,
+        created_at: now,
+        updated_at: now,
+    };
+
+    // Store the new Promotion in the storage
+    store_promotion(&promotion);
+
+    // Return the new Promotion
+    Ok(promotion)
+}
+
+// Define update functions to replace the terms of a Promotion, keeping its redemptions
+#[ic_cdk::update]
+fn update_promotion(id: u64, payload: PromotionPayload) -> Result<Promotion, Error> {
+    require_role(&[Role::Admin])?;
+
+    // Validate the payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let promotion = get_promotion(id)?;
+    check_promotion_terms(&payload, Some(id))?;
+
+    // Update the Promotion in the storage
+    let promotion = Promotion {
+        code: payload.code.trim().to_string(),
+        discount: payload.discount,
+        min_subtotal: payload.min_subtotal,
+        valid_from: payload.valid_from,
+        valid_until: payload.valid_until,
+        max_redemptions: payload.max_redemptions,
+        max_redemptions_per_client: payload.max_redemptions_per_client,
+        first_order_only: payload.first_order_only,
+        categories: payload.categories,
+        restaurant_ids: payload.restaurant_ids,
+        updated_at: env::time(),
+        ..promotion
+    };
+    store_promotion(&promotion);
+
+    Ok(promotion)
+}
+
+// Define update functions to delete a Promotion; orders placed with it keep their discount
+#[ic_cdk::update]
+fn delete_promotion(id: u64) -> Result<String, Error> {
+    require_role(&[Role::Admin])?;
+
+    // Delete the Promotion, its code and its redemption counts from the storage
+    let promotion = get_promotion(id)?;
+    PROMOTION_STORAGE.with(|s| s.borrow_mut().remove(&id));
+    PROMOTION_CODE_INDEX.with(|s| s.borrow_mut().remove(&promo_code_key(&promotion.code)));
+    PROMOTION_REDEMPTIONS.with(|s| {
+        let mut redemptions = s.borrow_mut();
+        let keys: Vec<(u64, u64)> = redemptions
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            redemptions.remove(&key);
+        }
+    });
+
+    Ok(format!("Promotion id: {} deleted", id))
 }
 
 // Define an Error enum for handling errors
 #[derive(candid::CandidType, Deserialize, Serialize)]
 enum Error {
-    NotFound { msg: String },
-    AlreadyDelivered { msg: String },
-    InvalidPayload { msg: String },
-    Unauthorized { msg: String },
+    NotFound {
+        msg: String,
+    },
+    AlreadyDelivered {
+        msg: String,
+    },
+    InvalidPayload {
+        msg: String,
+    },
+    Unauthorized {
+        msg: String,
+    },
+    InvalidTransition {
+        msg: String,
+    },
+    Forbidden {
+        msg: String,
+    },
+    InvalidOrder {
+        msg: String,
+        problems: Vec<OrderProblem>,
+    },
+    Overflow {
+        msg: String,
+    },
+    PaymentFailed {
+        msg: String,
+    },
+    InvalidPromotion {
+        msg: String,
+    },
+}
+
+fn set_base_currency(currency: Currency) {
+    CONFIG
+        .with(|c| {
+            let mut config = c.borrow().get().clone();
+            config.base_currency = Some(currency);
+            c.borrow_mut().set(config)
+        })
+        .expect("Cannot update the config");
+}
+
+fn set_layout_version(version: u32) {
+    CONFIG
+        .with(|c| {
+            let mut config = c.borrow().get().clone();
+            config.layout_version = Some(version);
+            c.borrow_mut().set(config)
+        })
+        .expect("Cannot update the config");
+}
+
+// Bootstrap the installing controller as the first admin and fix the base currency,
+// which is US dollars unless given
+#[ic_cdk::init]
+fn init(base_currency: Option<Currency>) {
+    add_role(env::caller(), Role::Admin);
+    set_base_currency(base_currency.unwrap_or_default());
+    set_layout_version(STORAGE_LAYOUT_VERSION);
+    ic_cdk_timers::set_timer_interval(LOCATION_PRUNE_INTERVAL, prune_order_locations);
+}
+
+// Migrate orders stored in earlier layouts after an upgrade, and bootstrap the upgrading controller as admin if the canister predates roles
+#[ic_cdk::post_upgrade]
+fn post_upgrade(base_currency: Option<Currency>) {
+    migrate_stored_data(base_currency);
+
+    // Timers do not survive upgrades, so schedule the pruning again
+    ic_cdk_timers::set_timer_interval(LOCATION_PRUNE_INTERVAL, prune_order_locations);
+}
+
+// Bring the data stored by an earlier version up to date
+fn migrate_stored_data(base_currency: Option<Currency>) {
+    // Canisters installed before currencies existed choose theirs on the first upgrade; amounts
+    // stored until then are read in it
+    if CONFIG.with(|c| c.borrow().get().base_currency.is_none()) {
+        set_base_currency(base_currency.unwrap_or_default());
+    }
+
+    let has_admin = ROLE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .any(|(_, set)| set.roles.contains(&Role::Admin))
+    });
+    if !has_admin {
+        add_role(env::caller(), Role::Admin);
+    }
+
+    // The rest scans all of the stored data, so it only runs once per layout
+    if CONFIG.with(|c| c.borrow().get().layout_version) >= Some(STORAGE_LAYOUT_VERSION) {
+        return;
+    }
+
+    // Rewrite amounts stored without a currency before anything reads them, build the
+    // secondary indexes for data stored before they existed, then move the orders of the
+    // earliest layout, which are indexed as they move
+    rewrite_plain_amounts();
+    backfill_indexes();
+    migrate_legacy_orders();
+    detach_anonymous_reviews();
+    set_layout_version(STORAGE_LAYOUT_VERSION);
 }
 
 // Candid generator for exporting the Candid interface
@@ -0,0 +1,191 @@
+use super::*;
+
+const OTHER_OWNER: u8 = 5;
+const OTHER_COURIER: u8 = 7;
+
+fn is_forbidden(error: Error) -> bool {
+    matches!(error, Error::Forbidden { .. })
+}
+
+// Place an order at the restaurant of 'OWNER', and give 'OTHER_OWNER' a restaurant of its own
+fn order_with_rival_restaurant() -> Order {
+    let (restaurant, client_id) = setup();
+    add_role(principal(OTHER_OWNER), Role::RestaurantStaff);
+    as_caller(OTHER_OWNER);
+    ok(create_restaurant(RestaurantPayload {
+        name: "Trattoria".to_string(),
+        address: "3 Main Street".to_string(),
+        latitude: 0.0,
+        longitude: 0.0,
+        opening_hours: Vec::new(),
+    }));
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    place_order(client_id, &[(item.id, 1)])
+}
+
+#[test]
+fn restaurant_transitions_need_the_owner_of_the_order_restaurant() {
+    let order = order_with_rival_restaurant();
+
+    assert!(is_forbidden(err(set_status(
+        OTHER_OWNER,
+        order.id,
+        OrderStatus::Accepted
+    ))));
+    assert_eq!(super::order(order.id).status, OrderStatus::Placed);
+
+    ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+    ok(set_status(ADMIN, order.id, OrderStatus::Preparing));
+    assert_eq!(super::order(order.id).status, OrderStatus::Preparing);
+}
+
+#[test]
+fn only_the_owner_of_the_order_restaurant_assigns_couriers() {
+    let order = order_with_rival_restaurant();
+    let courier = assign(order.id);
+
+    as_caller(OTHER_OWNER);
+    assert!(is_forbidden(err(assign_courier(order.id, courier.id))));
+    let assignment = super::order(order.id).assignment.unwrap();
+    assert!(assignment.accepted);
+}
+
+#[test]
+fn only_the_assigned_courier_delivers() {
+    let order = order_with_rival_restaurant();
+    ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+    assign(order.id);
+    for status in [OrderStatus::Preparing, OrderStatus::ReadyForPickup] {
+        ok(set_status(OWNER, order.id, status));
+    }
+    // Couriers cannot report restaurant-side progress
+    assert!(is_forbidden(err(set_status(
+        COURIER,
+        order.id,
+        OrderStatus::Accepted
+    ))));
+    ok(set_status(COURIER, order.id, OrderStatus::PickedUp));
+    ok(set_status(COURIER, order.id, OrderStatus::InTransit));
+
+    // Callers that are not couriers at all cannot deliver either
+    for caller in [OWNER, ADMIN] {
+        assert!(set_status(caller, order.id, OrderStatus::Delivered).is_err());
+    }
+    assert_eq!(super::order(order.id).status, OrderStatus::InTransit);
+    ok(set_status(COURIER, order.id, OrderStatus::Delivered));
+    assert_eq!(super::order(order.id).status, OrderStatus::Delivered);
+}
+
+// Store items left without a restaurant, one never given one and one whose restaurant is gone
+// Read an order, its timeline, refund and ledger, keeping only whether each read was refused
+fn order_reads(order_id: u64) -> [bool; 4] {
+    let refused = |error: Error| matches!(error, Error::Forbidden { .. });
+    [
+        get_order_by_id(order_id).map(|_| ()),
+        get_order_timeline(order_id, Page::default()).map(|_| ()),
+        get_order_refund(order_id).map(|_| ()),
+        get_order_ledger(order_id).map(|_| ()),
+    ]
+    .map(|read| read.err().is_some_and(refused))
+}
+
+#[test]
+fn orders_are_read_by_their_parties_only() {
+    let order = order_with_rival_restaurant();
+    ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+    assign(order.id);
+    add_role(principal(OTHER_COURIER), Role::Courier);
+    as_caller(OTHER_COURIER);
+    ok(register_courier(CourierPayload {
+        name: "Rival".to_string(),
+        vehicle: VehicleType::Bicycle,
+    }));
+
+    for caller in [CLIENT, OWNER, COURIER, ADMIN] {
+        as_caller(caller);
+        assert_eq!(order_reads(order.id), [false; 4]);
+    }
+    for caller in [OTHER_OWNER, OTHER_COURIER] {
+        as_caller(caller);
+        assert_eq!(order_reads(order.id), [true; 4]);
+    }
+}
+
+fn unowned_items() -> [Item; 2] {
+    let (restaurant, _) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let orphans = [
+        Item {
+            id: 1_000,
+            restaurant_id: None,
+            ..item.clone()
+        },
+        Item {
+            id: 1_001,
+            restaurant_id: Some(999),
+            ..item
+        },
+    ];
+    for orphan in &orphans {
+        store_item(orphan);
+    }
+    orphans
+}
+
+#[test]
+fn only_admins_archive_items_without_a_restaurant() {
+    for item in unowned_items() {
+        as_caller(OWNER);
+        assert!(is_forbidden(err(delete_food_item_by_id(item.id))));
+        assert!(!ok(get_food_item_by_id(item.id)).is_archived());
+
+        as_caller(ADMIN);
+        ok(delete_food_item_by_id(item.id));
+        assert!(ok(get_food_item_by_id(item.id)).is_archived());
+    }
+}
+
+#[test]
+fn only_admins_update_items_without_a_restaurant() {
+    let rename = || ItemUpdate {
+        name: Some("Marinara".to_string()),
+        ..ItemUpdate::default()
+    };
+    for item in unowned_items() {
+        as_caller(OWNER);
+        assert!(is_forbidden(err(update_food_item(item.id, rename()))));
+        assert_eq!(ok(get_food_item_by_id(item.id)).name, "Margherita");
+
+        as_caller(ADMIN);
+        assert_eq!(ok(update_food_item(item.id, rename())).name, "Marinara");
+    }
+}
+
+#[test]
+fn only_the_courier_and_admins_see_where_a_courier_is() {
+    let order = order_with_rival_restaurant();
+    ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+    let courier = assign(order.id);
+    let home = Location {
+        latitude: 45.0,
+        longitude: 9.0,
+    };
+    as_caller(COURIER);
+    ok(go_online(Some(home)));
+    ok(report_location(order.id, 45.1, 9.1, 5.0));
+
+    // Reported points stay on the route of the order
+    assert_eq!(ok(get_courier_by_id(courier.id)).location, Some(home));
+    as_caller(ADMIN);
+    assert_eq!(ok(get_courier_by_id(courier.id)).location, Some(home));
+    for caller in [OWNER, OTHER_OWNER, CLIENT] {
+        as_caller(caller);
+        assert_eq!(ok(get_courier_by_id(courier.id)).location, None);
+    }
+    as_caller(OWNER);
+    let available = ok(get_available_couriers(Page::default()));
+    assert!(available
+        .items
+        .iter()
+        .all(|courier| courier.location.is_none()));
+}
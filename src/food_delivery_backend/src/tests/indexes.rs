@@ -0,0 +1,416 @@
+use super::*;
+
+// Every index entry that points at an item, by index
+#[derive(Debug, PartialEq)]
+struct ItemEntries {
+    categories: Vec<CategoryKey>,
+    restaurants: Vec<u64>,
+    tokens: Vec<TokenKey>,
+    reviews: Vec<u64>,
+}
+
+type Index<K> = std::thread::LocalKey<RefCell<StableBTreeMap<(K, u64), (), Memory>>>;
+
+// Leading keys of the entries of a (key, id) index that point at an id
+fn keys_of<K>(index: &'static Index<K>, id: u64) -> Vec<K>
+where
+    K: Ord + Clone,
+    (K, u64): BoundedStorable,
+{
+    index.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|((_, entry_id), _)| *entry_id == id)
+            .map(|((key, _), _)| key)
+            .collect()
+    })
+}
+
+fn item_entries(item_id: u64) -> ItemEntries {
+    ItemEntries {
+        categories: keys_of(&CATEGORY_ITEM_INDEX, item_id),
+        restaurants: keys_of(&RESTAURANT_ITEM_INDEX, item_id),
+        tokens: keys_of(&SEARCH_INDEX, item_id),
+        reviews: ITEM_REVIEW_INDEX.with(|s| index_ids(&s.borrow(), item_id)),
+    }
+}
+
+fn entries_for(item: &Item, reviews: Vec<u64>) -> ItemEntries {
+    ItemEntries {
+        categories: vec![category_key(&item.category)],
+        restaurants: item.restaurant_id.into_iter().collect(),
+        tokens: item_tokens(item),
+        reviews,
+    }
+}
+
+#[test]
+fn updating_an_item_moves_its_index_entries() {
+    let (restaurant, _) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    assert_eq!(item_entries(item.id), entries_for(&item, Vec::new()));
+
+    as_caller(OWNER);
+    let updated = ok(update_food_item(
+        item.id,
+        ItemUpdate {
+            name: Some("Marinara".to_string()),
+            description: Some("Tomato and garlic".to_string()),
+            category: Some("Classics".to_string()),
+            ..ItemUpdate::default()
+        },
+    ));
+
+    assert_eq!(item_entries(item.id), entries_for(&updated, Vec::new()));
+    assert!(!keys_of(&SEARCH_INDEX, item.id).contains(&key_blob("margherita")));
+    assert!(CATEGORY_ITEM_INDEX.with(|s| index_ids(&s.borrow(), category_key("pizza")).is_empty()));
+    assert_eq!(
+        CATEGORY_ITEM_INDEX.with(|s| index_ids(&s.borrow(), category_key("classics"))),
+        vec![item.id]
+    );
+}
+
+#[test]
+fn archiving_an_item_keeps_its_index_entries_but_unlists_it() {
+    let (restaurant, _) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+
+    as_caller(OWNER);
+    ok(delete_food_item_by_id(item.id));
+
+    let archived = ok(get_food_item_by_id(item.id));
+    assert!(archived.is_archived());
+    assert_eq!(item_entries(item.id), entries_for(&archived, Vec::new()));
+    assert!(get_food_items_by_category("pizza".to_string(), None).is_err());
+}
+
+#[test]
+fn purging_an_item_removes_it_and_its_reviews_from_every_index() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let other = add_item(restaurant.id, "Calzone", "pizza", 1_200);
+    let order = delivered_order(client_id, &[(item.id, 1), (other.id, 1)]);
+    let review = review(order.id, item.id, 4);
+    let kept = super::review(order.id, other.id, 5);
+    assert_eq!(item_entries(item.id), entries_for(&item, vec![review.id]));
+
+    as_caller(OWNER);
+    ok(delete_food_item_by_id(item.id));
+    as_caller(ADMIN);
+    ok(purge_food_item(item.id));
+
+    assert!(ITEM_STORAGE.with(|s| s.borrow().get(&item.id)).is_none());
+    assert_eq!(
+        item_entries(item.id),
+        ItemEntries {
+            categories: Vec::new(),
+            restaurants: Vec::new(),
+            tokens: Vec::new(),
+            reviews: Vec::new(),
+        }
+    );
+    assert!(keys_of(&CLIENT_REVIEW_INDEX, review.id).is_empty());
+    assert!(ORDER_ITEM_REVIEW_INDEX
+        .with(|s| s.borrow().get(&(order.id, item.id)))
+        .is_none());
+    assert!(ITEM_ORDER_COUNTS
+        .with(|s| s.borrow().get(&item.id))
+        .is_none());
+
+    // The other item of the order keeps its entries
+    assert_eq!(item_entries(other.id), entries_for(&other, vec![kept.id]));
+    assert_eq!(keys_of(&CLIENT_REVIEW_INDEX, kept.id), vec![client_id]);
+}
+
+#[test]
+fn order_status_index_follows_status_changes() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let order = place_order(client_id, &[(item.id, 1)]);
+    let statuses = || keys_of(&ORDER_STATUS_INDEX, order.id);
+    assert_eq!(statuses(), vec![OrderStatus::Placed as u64]);
+
+    ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+    assert_eq!(statuses(), vec![OrderStatus::Accepted as u64]);
+
+    as_caller(OWNER);
+    ok(cancel_order(
+        order.id,
+        CancellationReason::ItemsUnavailable,
+        None,
+    ));
+    assert_eq!(statuses(), vec![OrderStatus::Cancelled as u64]);
+    assert_eq!(keys_of(&CLIENT_ORDER_INDEX, order.id), vec![client_id]);
+}
+
+#[test]
+fn courier_order_index_pages_the_assigned_orders() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let orders: Vec<Order> = (0..3)
+        .map(|_| {
+            let order = place_order(client_id, &[(item.id, 1)]);
+            ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+            order
+        })
+        .collect();
+    let courier = assign(orders[0].id);
+    assign(orders[2].id);
+    assert_eq!(
+        keys_of(&COURIER_ORDER_INDEX, orders[0].id),
+        vec![courier.id]
+    );
+    assert!(keys_of(&COURIER_ORDER_INDEX, orders[1].id).is_empty());
+
+    as_caller(COURIER);
+    let first = ok(get_orders_for_courier(
+        courier.id,
+        Page {
+            start_after: None,
+            limit: 1,
+        },
+    ));
+    assert_eq!(first.items[0].id, orders[0].id);
+    let rest = ok(get_orders_for_courier(
+        courier.id,
+        Page {
+            start_after: first.next_cursor,
+            limit: 1,
+        },
+    ));
+    assert_eq!(rest.items[0].id, orders[2].id);
+    assert_eq!(rest.next_cursor, None);
+
+    as_caller(CLIENT);
+    let mine: Vec<u64> = ok(get_my_orders(Page::default()))
+        .items
+        .iter()
+        .map(|order| order.id)
+        .collect();
+    assert_eq!(
+        mine,
+        orders.iter().map(|order| order.id).collect::<Vec<u64>>()
+    );
+}
+
+#[test]
+fn the_legacy_category_query_matches_whole_categories_through_the_index() {
+    let (restaurant, _) = setup();
+    let item = add_item(restaurant.id, "Margherita", "Pizza", 1_000);
+    add_item(restaurant.id, "Tiramisu", "dessert", 600);
+    let by_category = |text: &str| {
+        get_food_items_by_category(text.to_string(), None)
+            .map(|items| items.iter().map(|item| item.id).collect::<Vec<u64>>())
+    };
+
+    assert_eq!(ok(by_category(" pizza")), vec![item.id]);
+    assert!(matches!(err(by_category("izz")), Error::NotFound { .. }));
+    assert!(matches!(
+        err(by_category("from the menu")),
+        Error::NotFound { .. }
+    ));
+    assert!(matches!(
+        err(get_food_items_by_category(
+            "pizza".to_string(),
+            Some(restaurant.id + 100)
+        )),
+        Error::NotFound { .. }
+    ));
+
+    // The reply is bounded to one page; the paginated query serves the rest
+    for n in 0..MAX_PAGE_LIMIT {
+        add_item(restaurant.id, &format!("Pizza {}", n), "pizza", 1_000);
+    }
+    assert_eq!(ok(by_category("pizza")).len(), MAX_PAGE_LIMIT as usize);
+    let page = ok(get_food_items_by_category_v2(
+        "pizza".to_string(),
+        None,
+        Page::default(),
+    ));
+    assert_eq!(page.total, MAX_PAGE_LIMIT as u64 + 1);
+}
+
+#[test]
+fn the_largest_item_fits_its_storage() {
+    let (restaurant, _) = setup();
+    let pizza = |bytes: usize| "\u{1F355}".repeat(bytes / 4);
+    let payload = ItemPayload {
+        name: pizza(MAX_ITEM_NAME_BYTES),
+        description: pizza(MAX_ITEM_DESCRIPTION_BYTES),
+        price: usd(u64::MAX),
+        category: pizza(MAX_ITEM_CATEGORY_BYTES),
+        restaurant_id: restaurant.id,
+        prep_minutes: 240,
+        dietary_tags: (0..MAX_DIETARY_TAGS)
+            .map(|i| format!("{}{}", i, "a".repeat(MAX_DIETARY_TAG_BYTES - 1)))
+            .collect(),
+    };
+    for oversized in [
+        ItemPayload {
+            name: format!("{}a", payload.name),
+            ..payload.clone()
+        },
+        ItemPayload {
+            description: format!("{}a", payload.description),
+            ..payload.clone()
+        },
+        ItemPayload {
+            category: format!("{}a", payload.category),
+            ..payload.clone()
+        },
+    ] {
+        assert!(oversized.validate().is_err());
+    }
+
+    as_caller(OWNER);
+    let item = Item {
+        id: u64::MAX,
+        updated_at: Some(u64::MAX),
+        availability: Some(ItemAvailability::Hidden),
+        archived_at: Some(u64::MAX),
+        rating: Some(RatingStats {
+            count: u64::MAX,
+            sum: u64::MAX,
+            histogram: [u64::MAX; 5],
+        }),
+        ..ok(create_food_item(payload))
+    };
+    assert!(item.to_bytes().len() <= Item::MAX_SIZE as usize);
+}
+
+#[test]
+fn client_orders_are_paged_from_the_index_beyond_the_recent_ids() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let placed: Vec<u64> = (0..MAX_CLIENT_ORDER_IDS + 3)
+        .map(|_| place_order(client_id, &[(item.id, 1)]).id)
+        .collect();
+
+    // The client record keeps only the most recent ids
+    let client = CLIENT_STORAGE.with(|s| s.borrow().get(&client_id)).unwrap();
+    let recent: Vec<u64> = placed
+        .iter()
+        .rev()
+        .take(MAX_CLIENT_ORDER_IDS)
+        .copied()
+        .collect();
+    assert_eq!(client.order_ids, recent);
+
+    // The index pages through all of them
+    as_caller(CLIENT);
+    let mut listed = Vec::new();
+    let mut page = Page {
+        start_after: None,
+        limit: 10,
+    };
+    loop {
+        let result = ok(get_orders_by_client_id_v2(client_id, page));
+        assert_eq!(result.total, placed.len() as u64);
+        listed.extend(result.items.iter().map(|order| order.id));
+        match result.next_cursor {
+            Some(cursor) => page.start_after = Some(cursor),
+            None => break,
+        }
+    }
+    assert_eq!(listed, placed);
+}
+
+#[test]
+fn rebuilding_the_client_order_index_repairs_it() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let first = place_order(client_id, &[(item.id, 1)]);
+    let second = place_order(client_id, &[(item.id, 1)]);
+    // A missing entry, and one for an order of someone else
+    CLIENT_ORDER_INDEX.with(|s| {
+        s.borrow_mut().remove(&(client_id, first.id));
+        s.borrow_mut().insert((client_id + 1, second.id), ());
+    });
+
+    as_caller(CLIENT);
+    assert!(matches!(
+        err(rebuild_client_order_index()),
+        Error::Forbidden { .. }
+    ));
+    assert_eq!(client_order_ids(client_id), vec![second.id]);
+
+    as_caller(ADMIN);
+    ok(rebuild_client_order_index());
+    assert_eq!(client_order_ids(client_id), vec![first.id, second.id]);
+    assert!(client_order_ids(client_id + 1).is_empty());
+    let client = CLIENT_STORAGE.with(|s| s.borrow().get(&client_id)).unwrap();
+    assert_eq!(client.order_ids, vec![second.id, first.id]);
+}
+
+#[test]
+fn availability_decides_whether_an_item_is_listed_and_orderable() {
+    let (restaurant, client_id) = setup();
+    let available = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let sold_out = add_item(restaurant.id, "Calzone", "pizza", 1_200);
+    let hidden = add_item(restaurant.id, "Marinara", "pizza", 900);
+    let set_availability = |item: &Item, availability| {
+        as_caller(OWNER);
+        ok(update_food_item(
+            item.id,
+            ItemUpdate {
+                availability: Some(availability),
+                ..ItemUpdate::default()
+            },
+        ))
+    };
+    set_availability(&sold_out, ItemAvailability::SoldOut);
+    set_availability(&hidden, ItemAvailability::Hidden);
+    let listed = || -> Vec<u64> {
+        ok(get_food_items_by_category_v2(
+            "pizza".to_string(),
+            None,
+            Page::default(),
+        ))
+        .items
+        .iter()
+        .map(|item| item.id)
+        .collect()
+    };
+
+    // Sold out items stay on the menu, hidden ones do not; neither can be ordered
+    assert_eq!(listed(), vec![available.id, sold_out.id]);
+    as_caller(CLIENT);
+    for item in [&sold_out, &hidden] {
+        assert!(create_order(order_payload(client_id, &[(item.id, 1)])).is_err());
+    }
+    place_order(client_id, &[(available.id, 1)]);
+
+    set_availability(&hidden, ItemAvailability::Available);
+    assert_eq!(listed(), vec![available.id, sold_out.id, hidden.id]);
+    place_order(client_id, &[(hidden.id, 1)]);
+}
+
+#[test]
+fn only_admins_purge_and_only_archived_items() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let order = delivered_order(client_id, &[(item.id, 1)]);
+
+    as_caller(ADMIN);
+    assert!(matches!(
+        err(purge_food_item(item.id)),
+        Error::InvalidPayload { .. }
+    ));
+    as_caller(OWNER);
+    ok(delete_food_item_by_id(item.id));
+    assert!(matches!(
+        err(purge_food_item(item.id)),
+        Error::Forbidden { .. }
+    ));
+    assert!(ITEM_STORAGE.with(|s| s.borrow().get(&item.id)).is_some());
+
+    as_caller(ADMIN);
+    ok(purge_food_item(item.id));
+    assert!(matches!(
+        err(purge_food_item(item.id)),
+        Error::NotFound { .. }
+    ));
+    // Orders keep the snapshot of the purged item
+    let line = &super::order(order.id).lines[0];
+    assert_eq!((line.item_id, line.name.as_str()), (item.id, "Margherita"));
+}
@@ -0,0 +1,401 @@
+// Unit tests run against the in-memory stable structures of the test thread, with a fixed
+// caller and clock in place of the replica and an in-memory token ledger in place of an
+// ICRC-2 ledger canister
+
+use super::*;
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll, Waker};
+
+mod access;
+mod indexes;
+mod orders;
+mod payments;
+mod pricing;
+mod promotions;
+mod ratings;
+mod routes;
+mod search;
+mod sizes;
+mod transitions;
+mod upgrade;
+
+pub const ADMIN: u8 = 1;
+pub const OWNER: u8 = 2;
+pub const CLIENT: u8 = 3;
+pub const COURIER: u8 = 4;
+pub const LEDGER: u8 = 9;
+
+pub fn principal(n: u8) -> Principal {
+    Principal::from_slice(&[n; 10])
+}
+
+// Stand-ins for the caller, the clock and the canister id of a replica
+pub mod env {
+    use candid::Principal;
+    use std::cell::Cell;
+
+    thread_local! {
+        static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        static TIME: Cell<u64> = const { Cell::new(1_000_000_000) };
+    }
+
+    pub fn caller() -> Principal {
+        CALLER.with(|c| c.get())
+    }
+
+    pub fn time() -> u64 {
+        TIME.with(|t| t.get())
+    }
+
+    pub fn id() -> Principal {
+        Principal::from_slice(&[0xca; 10])
+    }
+
+    pub fn set_caller(principal: Principal) {
+        CALLER.with(|c| c.set(principal));
+    }
+
+    pub fn advance_time(nanos: u64) {
+        TIME.with(|t| t.set(t.get() + nanos));
+    }
+}
+
+// Stand-in for an ICRC-1/ICRC-2 ledger without fees, with one account per principal
+pub mod icrc {
+    use super::super::{Account, TransferArg, TransferError, TransferFromArgs, TransferFromError};
+    use super::env;
+    use candid::{Nat, Principal};
+    use ic_cdk::api::call::{CallResult, RejectionCode};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    // Identifies a transfer by (sender, memo, created at), as a real ledger deduplicates them
+    type TransactionKey = (Principal, Option<Vec<u8>>, Option<u64>);
+
+    #[derive(Default)]
+    pub struct MockLedger {
+        pub balances: HashMap<Principal, u64>,
+        // Keyed by (account owner, spender)
+        pub allowances: HashMap<(Principal, Principal), u64>,
+        transactions: HashMap<TransactionKey, u64>,
+        blocks: u64,
+        // Calls wait while this is set, so tests can interleave concurrent calls
+        pub held: bool,
+        // Calls are rejected as if the ledger canister could not be reached
+        pub unreachable: bool,
+        pub calls: u64,
+    }
+
+    thread_local! {
+        pub static LEDGER: RefCell<MockLedger> = RefCell::new(MockLedger::default());
+    }
+
+    fn amount(nat: &Nat) -> u64 {
+        nat.0.to_string().parse().expect("amounts fit in u64")
+    }
+
+    impl MockLedger {
+        fn dedup(&self, sender: Principal, memo: &Option<Vec<u8>>, at: Option<u64>) -> Option<u64> {
+            self.transactions.get(&(sender, memo.clone(), at)).copied()
+        }
+
+        fn record(&mut self, sender: Principal, memo: Option<Vec<u8>>, at: Option<u64>) -> Nat {
+            self.blocks += 1;
+            self.transactions.insert((sender, memo, at), self.blocks);
+            Nat::from(self.blocks)
+        }
+
+        fn credit(&mut self, to: &Account, amount: u64) {
+            *self.balances.entry(to.owner).or_insert(0) += amount;
+        }
+
+        fn transfer_from(&mut self, args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+            let spender = env::id();
+            let owner = args.from.owner;
+            if let Some(block) = self.dedup(owner, &args.memo, args.created_at_time) {
+                return Err(TransferFromError::Duplicate {
+                    duplicate_of: Nat::from(block),
+                });
+            }
+            let amount = amount(&args.amount);
+            let allowance = self.allowances.get(&(owner, spender)).copied().unwrap_or(0);
+            if allowance < amount {
+                return Err(TransferFromError::InsufficientAllowance {
+                    allowance: Nat::from(allowance),
+                });
+            }
+            let balance = self.balances.get(&owner).copied().unwrap_or(0);
+            if balance < amount {
+                return Err(TransferFromError::InsufficientFunds {
+                    balance: Nat::from(balance),
+                });
+            }
+            self.allowances.insert((owner, spender), allowance - amount);
+            self.balances.insert(owner, balance - amount);
+            self.credit(&args.to, amount);
+            Ok(self.record(owner, args.memo, args.created_at_time))
+        }
+
+        fn transfer(&mut self, args: TransferArg) -> Result<Nat, TransferError> {
+            let sender = env::id();
+            if let Some(block) = self.dedup(sender, &args.memo, args.created_at_time) {
+                return Err(TransferError::Duplicate {
+                    duplicate_of: Nat::from(block),
+                });
+            }
+            let amount = amount(&args.amount);
+            let balance = self.balances.get(&sender).copied().unwrap_or(0);
+            if balance < amount {
+                return Err(TransferError::InsufficientFunds {
+                    balance: Nat::from(balance),
+                });
+            }
+            self.balances.insert(sender, balance - amount);
+            self.credit(&args.to, amount);
+            Ok(self.record(sender, args.memo, args.created_at_time))
+        }
+    }
+
+    // Pending while the ledger holds calls
+    struct Hold;
+
+    impl Future for Hold {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+            if LEDGER.with(|l| l.borrow().held) {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        }
+    }
+
+    fn unreachable<T>() -> CallResult<T> {
+        Err((
+            RejectionCode::DestinationInvalid,
+            "ledger is unreachable".to_string(),
+        ))
+    }
+
+    pub async fn icrc2_transfer_from(
+        _ledger: Principal,
+        args: TransferFromArgs,
+    ) -> CallResult<(Result<Nat, TransferFromError>,)> {
+        LEDGER.with(|l| l.borrow_mut().calls += 1);
+        Hold.await;
+        if LEDGER.with(|l| l.borrow().unreachable) {
+            return unreachable();
+        }
+        Ok((LEDGER.with(|l| l.borrow_mut().transfer_from(args)),))
+    }
+
+    pub async fn icrc1_transfer(
+        _ledger: Principal,
+        args: TransferArg,
+    ) -> CallResult<(Result<Nat, TransferError>,)> {
+        LEDGER.with(|l| l.borrow_mut().calls += 1);
+        Hold.await;
+        if LEDGER.with(|l| l.borrow().unreachable) {
+            return unreachable();
+        }
+        Ok((LEDGER.with(|l| l.borrow_mut().transfer(args)),))
+    }
+
+    pub fn balance(owner: Principal) -> u64 {
+        LEDGER.with(|l| l.borrow().balances.get(&owner).copied().unwrap_or(0))
+    }
+
+    pub fn mint(owner: Principal, amount: u64) {
+        LEDGER.with(|l| *l.borrow_mut().balances.entry(owner).or_insert(0) += amount);
+    }
+
+    pub fn approve(owner: Principal, amount: u64) {
+        LEDGER.with(|l| {
+            l.borrow_mut().allowances.insert((owner, env::id()), amount);
+        });
+    }
+
+    pub fn hold(held: bool) {
+        LEDGER.with(|l| l.borrow_mut().held = held);
+    }
+
+    pub fn set_unreachable(unreachable: bool) {
+        LEDGER.with(|l| l.borrow_mut().unreachable = unreachable);
+    }
+
+    pub fn calls() -> u64 {
+        LEDGER.with(|l| l.borrow().calls)
+    }
+}
+
+// Poll a future once, as the replica does until a call it awaits returns
+pub fn poll_once<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(Waker::noop()))
+}
+
+// Run a future whose ledger calls are not held to completion
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    match poll_once(pin!(future)) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("the future waits on a held ledger call"),
+    }
+}
+
+pub fn ok<T>(result: Result<T, Error>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => panic!("unexpected error: {}", serde_json::to_string(&e).unwrap()),
+    }
+}
+
+pub fn err<T>(result: Result<T, Error>) -> Error {
+    match result {
+        Ok(_) => panic!("expected an error"),
+        Err(e) => e,
+    }
+}
+
+pub fn as_caller(n: u8) {
+    env::set_caller(principal(n));
+}
+
+pub fn usd(amount_minor: u64) -> Money {
+    Money::new(amount_minor, Currency::Usd)
+}
+
+// Set up an admin, a restaurant of 'OWNER' and a registered client bound to 'CLIENT'
+pub fn setup() -> (Restaurant, u64) {
+    set_base_currency(Currency::Usd);
+    add_role(principal(ADMIN), Role::Admin);
+    add_role(principal(OWNER), Role::RestaurantStaff);
+    as_caller(OWNER);
+    let restaurant = ok(create_restaurant(RestaurantPayload {
+        name: "Pizzeria".to_string(),
+        address: "1 Main Street".to_string(),
+        latitude: 0.0,
+        longitude: 0.0,
+        opening_hours: Vec::new(),
+    }));
+    as_caller(CLIENT);
+    let client = ok(create_client(ClientPayload {
+        name: "Client".to_string(),
+        address: "2 Main Street".to_string(),
+        phone: "555-0100".to_string(),
+        email: "client@example.com".to_string(),
+    }));
+    (restaurant, client.id)
+}
+
+pub fn add_item(restaurant_id: u64, name: &str, category: &str, price: u64) -> Item {
+    as_caller(OWNER);
+    ok(create_food_item(ItemPayload {
+        name: name.to_string(),
+        description: format!("{} from the menu", name),
+        price: usd(price),
+        category: category.to_string(),
+        restaurant_id,
+        prep_minutes: 10,
+        dietary_tags: Vec::new(),
+    }))
+}
+
+pub fn promotion_payload(code: &str, discount: Discount) -> PromotionPayload {
+    PromotionPayload {
+        code: code.to_string(),
+        discount,
+        min_subtotal: None,
+        valid_from: 0,
+        valid_until: None,
+        max_redemptions: None,
+        max_redemptions_per_client: None,
+        first_order_only: false,
+        categories: Vec::new(),
+        restaurant_ids: Vec::new(),
+    }
+}
+
+pub fn order_payload(client_id: u64, items: &[(u64, u64)]) -> OrderPayload {
+    OrderPayload {
+        client_id,
+        items: items
+            .iter()
+            .map(|&(item_id, quantity)| OrderItem { item_id, quantity })
+            .collect(),
+        delivery_location: None,
+        tip: None,
+        promo_code: None,
+    }
+}
+
+pub fn place_order(client_id: u64, items: &[(u64, u64)]) -> Order {
+    as_caller(CLIENT);
+    ok(create_order(order_payload(client_id, items)))
+}
+
+// Register 'COURIER', assign it to an order and have it accept
+pub fn assign(order_id: u64) -> Courier {
+    add_role(principal(COURIER), Role::Courier);
+    as_caller(COURIER);
+    let courier = match caller_courier_id() {
+        Ok(id) => ok(get_courier_by_id(id)),
+        Err(_) => ok(register_courier(CourierPayload {
+            name: "Courier".to_string(),
+            vehicle: VehicleType::Bicycle,
+        })),
+    };
+    ok(go_online(None));
+    as_caller(OWNER);
+    ok(assign_courier(order_id, courier.id));
+    as_caller(COURIER);
+    ok(accept_assignment(order_id));
+    courier
+}
+
+// Move an accepted, assigned order through to delivery
+pub fn deliver(order_id: u64) {
+    for status in [OrderStatus::Preparing, OrderStatus::ReadyForPickup] {
+        ok(set_status(OWNER, order_id, status));
+    }
+    for status in [
+        OrderStatus::PickedUp,
+        OrderStatus::InTransit,
+        OrderStatus::Delivered,
+    ] {
+        ok(set_status(COURIER, order_id, status));
+    }
+}
+
+// Place an order and take it all the way to delivery
+pub fn delivered_order(client_id: u64, items: &[(u64, u64)]) -> Order {
+    let order = place_order(client_id, items);
+    ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+    assign(order.id);
+    deliver(order.id);
+    self::order(order.id)
+}
+
+pub fn review(order_id: u64, item_id: u64, rating: u64) -> Review {
+    as_caller(CLIENT);
+    ok(create_review(ReviewPayload {
+        item_id,
+        order_id,
+        rating,
+        comment: "Would order again".to_string(),
+    }))
+}
+
+pub fn set_status(caller: u8, order_id: u64, status: OrderStatus) -> Result<String, Error> {
+    as_caller(caller);
+    update_order_status(order_id, status, None)
+}
+
+pub fn order(id: u64) -> Order {
+    ORDER_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .expect("order exists")
+}
@@ -0,0 +1,107 @@
+use super::*;
+use serde_json::{json, Value};
+
+// Place an order as an admin, who may order for any client, expecting it to be rejected and
+// returning its problems as JSON
+fn problems(payload: OrderPayload) -> Value {
+    as_caller(ADMIN);
+    match err(create_order(payload)) {
+        Error::InvalidOrder { problems, .. } => serde_json::to_value(problems).unwrap(),
+        other => panic!(
+            "expected an invalid order, got {}",
+            serde_json::to_string(&other).unwrap()
+        ),
+    }
+}
+
+#[test]
+fn an_empty_cart_is_reported() {
+    let (_, client_id) = setup();
+    assert_eq!(
+        problems(order_payload(client_id, &[])),
+        json!(["EmptyCart"])
+    );
+}
+
+#[test]
+fn every_problem_of_a_cart_is_reported_with_its_line() {
+    let (restaurant, _) = setup();
+    let pizza = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let sold_out = add_item(restaurant.id, "Calzone", "pizza", 1_200);
+    as_caller(OWNER);
+    ok(update_food_item(
+        sold_out.id,
+        ItemUpdate {
+            availability: Some(ItemAvailability::SoldOut),
+            ..ItemUpdate::default()
+        },
+    ));
+    // Items priced before the base currency was enforced may still be in another currency
+    let foreign = Item {
+        id: 1_000,
+        price: Money::new(900, Currency::Eur),
+        ..add_item(restaurant.id, "Marinara", "pizza", 900)
+    };
+    ITEM_STORAGE.with(|s| s.borrow_mut().insert(foreign.id, foreign.clone()));
+
+    let payload = order_payload(
+        404,
+        &[
+            (pizza.id, 30),
+            (9_999, 1),
+            (pizza.id, 0),
+            (sold_out.id, 1),
+            (foreign.id, 1),
+            (pizza.id, 30),
+        ],
+    );
+
+    assert_eq!(
+        problems(payload),
+        json!([
+            {"MissingClient": {"client_id": 404}},
+            {"UnknownItem": {"line": 1, "item_id": 9_999}},
+            {"ZeroQuantity": {"line": 2, "item_id": pizza.id}},
+            {"ItemUnavailable": {"line": 3, "item_id": sold_out.id}},
+            {"MixedCurrencies": {"line": 4, "item_id": foreign.id, "currency": "Eur"}},
+            {"QuantityAboveLimit": {
+                "line": 5,
+                "item_id": pizza.id,
+                "quantity": 60,
+                "max": DEFAULT_MAX_ITEM_QUANTITY
+            }},
+        ])
+    );
+}
+
+#[test]
+fn too_many_distinct_items_are_reported() {
+    let (restaurant, client_id) = setup();
+    let pizza = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let mut lines: Vec<(u64, u64)> = vec![(pizza.id, 1)];
+    lines.extend((0..MAX_ORDER_LINES as u64).map(|n| (10_000 + n, 1)));
+
+    let reported = problems(order_payload(client_id, &lines));
+
+    assert_eq!(
+        reported[0],
+        json!({"TooManyLines": {"lines": MAX_ORDER_LINES + 1, "max": MAX_ORDER_LINES}})
+    );
+    assert_eq!(reported.as_array().unwrap().len(), 1 + MAX_ORDER_LINES);
+    assert_eq!(
+        reported[1],
+        json!({"UnknownItem": {"line": 1, "item_id": 10_000}})
+    );
+}
+
+#[test]
+fn a_rejected_cart_does_not_allocate_an_order() {
+    let (restaurant, client_id) = setup();
+    let pizza = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let before = ID_COUNTER.with(|c| *c.borrow().get());
+
+    problems(order_payload(client_id, &[(pizza.id, 0)]));
+
+    assert_eq!(ID_COUNTER.with(|c| *c.borrow().get()), before);
+    assert_eq!(ORDER_STORAGE.with(|s| s.borrow().len()), 0);
+}
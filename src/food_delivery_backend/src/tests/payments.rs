@@ -0,0 +1,354 @@
+use super::*;
+
+// Set up a ledger-backed canister, with the client holding 50.00 on the ledger
+fn ledger_setup() -> (Restaurant, u64) {
+    let (restaurant, client_id) = setup();
+    as_caller(ADMIN);
+    ok(update_config(Config {
+        ledger_canister_id: Some(principal(LEDGER)),
+        ..get_config()
+    }));
+    icrc::mint(principal(CLIENT), 5_000);
+    (restaurant, client_id)
+}
+
+// Place an order of two items at 10.00 on a ledger-backed canister
+fn placed_order() -> Order {
+    let (restaurant, client_id) = ledger_setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    place_order(client_id, &[(item.id, 2)])
+}
+
+fn pay(order_id: u64) -> Result<Order, Error> {
+    as_caller(CLIENT);
+    block_on(pay_order(order_id))
+}
+
+fn ledger_entries(order_id: u64) -> Vec<LedgerEntry> {
+    as_caller(ADMIN);
+    ok(get_order_ledger(order_id))
+}
+
+fn no_calls_in_flight() -> bool {
+    LEDGER_CALLS_IN_FLIGHT.with(|s| s.borrow().is_empty())
+}
+
+#[test]
+fn pay_order_moves_the_total_and_marks_the_order_paid() {
+    let order = placed_order();
+    icrc::approve(principal(CLIENT), 2_000);
+
+    let paid = ok(pay(order.id));
+
+    assert_eq!(paid.status, OrderStatus::Paid);
+    assert_eq!(paid.amount_captured, Some(usd(2_000)));
+    assert_eq!(icrc::balance(principal(CLIENT)), 3_000);
+    assert_eq!(icrc::balance(env::id()), 2_000);
+    let entries = ledger_entries(order.id);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].kind, LedgerEntryKind::Payment);
+    assert_eq!(entries[0].amount, usd(2_000));
+    assert!(entries[0].block_index.is_some());
+    assert!(no_calls_in_flight());
+    assert!(matches!(
+        err(pay(order.id)),
+        Error::InvalidTransition { .. }
+    ));
+}
+
+#[test]
+fn pay_order_with_insufficient_allowance_leaves_the_order_unpaid() {
+    let order = placed_order();
+    icrc::approve(principal(CLIENT), 500);
+
+    assert!(matches!(err(pay(order.id)), Error::PaymentFailed { .. }));
+
+    let order = super::order(order.id);
+    assert_eq!(order.status, OrderStatus::Placed);
+    assert_eq!(order.amount_captured, None);
+    assert!(ledger_entries(order.id).is_empty());
+    assert_eq!(icrc::balance(principal(CLIENT)), 5_000);
+    assert!(no_calls_in_flight());
+    // An unpaid order cannot move on while a ledger is configured
+    assert!(matches!(
+        err(set_status(OWNER, order.id, OrderStatus::Accepted)),
+        Error::InvalidTransition { .. }
+    ));
+
+    // The failed call released its lock, so the client can pay after approving enough
+    icrc::approve(principal(CLIENT), 2_000);
+    assert_eq!(ok(pay(order.id)).status, OrderStatus::Paid);
+}
+
+#[test]
+fn pay_order_with_an_unreachable_ledger_leaves_the_order_unpaid() {
+    let order = placed_order();
+    icrc::approve(principal(CLIENT), 2_000);
+    icrc::set_unreachable(true);
+
+    assert!(matches!(err(pay(order.id)), Error::PaymentFailed { .. }));
+    assert_eq!(super::order(order.id).status, OrderStatus::Placed);
+    assert!(no_calls_in_flight());
+
+    icrc::set_unreachable(false);
+    assert_eq!(ok(pay(order.id)).status, OrderStatus::Paid);
+}
+
+#[test]
+fn pay_order_rejects_a_second_call_while_the_first_is_in_flight() {
+    let order = placed_order();
+    icrc::approve(principal(CLIENT), 5_000);
+    icrc::hold(true);
+    as_caller(CLIENT);
+    let mut first = pin!(pay_order(order.id));
+    assert!(poll_once(first.as_mut()).is_pending());
+
+    assert!(matches!(err(pay(order.id)), Error::PaymentFailed { .. }));
+    assert_eq!(icrc::calls(), 1);
+
+    icrc::hold(false);
+    let paid = match poll_once(first) {
+        Poll::Ready(result) => ok(result),
+        Poll::Pending => panic!("the released call is still pending"),
+    };
+    assert_eq!(paid.status, OrderStatus::Paid);
+    assert_eq!(icrc::balance(principal(CLIENT)), 3_000);
+    assert_eq!(ledger_entries(order.id).len(), 1);
+    assert!(no_calls_in_flight());
+}
+
+#[test]
+fn payment_of_an_order_cancelled_while_paying_is_refunded() {
+    let order = placed_order();
+    icrc::approve(principal(CLIENT), 2_000);
+    icrc::hold(true);
+    as_caller(CLIENT);
+    let mut payment = pin!(pay_order(order.id));
+    assert!(poll_once(payment.as_mut()).is_pending());
+
+    ok(cancel_order(
+        order.id,
+        CancellationReason::ChangedMind,
+        None,
+    ));
+    icrc::hold(false);
+    let order = match poll_once(payment) {
+        Poll::Ready(result) => ok(result),
+        Poll::Pending => panic!("the released call is still pending"),
+    };
+
+    assert_eq!(order.status, OrderStatus::Cancelled);
+    assert_eq!(order.amount_captured, Some(usd(2_000)));
+    as_caller(CLIENT);
+    let refund = ok(get_order_refund(order.id));
+    assert_eq!(refund.amount, usd(2_000));
+    assert_eq!(refund.settled_at, None);
+}
+
+#[test]
+fn settle_refund_pays_back_a_cancelled_order() {
+    let order = placed_order();
+    icrc::approve(principal(CLIENT), 2_000);
+    ok(pay(order.id));
+    as_caller(CLIENT);
+    ok(cancel_order(
+        order.id,
+        CancellationReason::ChangedMind,
+        None,
+    ));
+
+    as_caller(OWNER);
+    assert!(matches!(
+        err(block_on(settle_refund(order.id))),
+        Error::Forbidden { .. }
+    ));
+
+    as_caller(ADMIN);
+    let refund = ok(block_on(settle_refund(order.id)));
+
+    assert!(refund.settled_at.is_some());
+    assert_eq!(icrc::balance(principal(CLIENT)), 5_000);
+    assert_eq!(icrc::balance(env::id()), 0);
+    assert_eq!(super::order(order.id).status, OrderStatus::Refunded);
+    let kinds: Vec<LedgerEntryKind> = ledger_entries(order.id)
+        .iter()
+        .map(|entry| entry.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![LedgerEntryKind::Payment, LedgerEntryKind::Refund]
+    );
+    assert!(no_calls_in_flight());
+    as_caller(ADMIN);
+    assert!(matches!(
+        err(block_on(settle_refund(order.id))),
+        Error::InvalidPayload { .. }
+    ));
+}
+
+#[test]
+fn failed_refund_stays_owed() {
+    let order = placed_order();
+    icrc::approve(principal(CLIENT), 2_000);
+    ok(pay(order.id));
+    as_caller(CLIENT);
+    ok(cancel_order(
+        order.id,
+        CancellationReason::ChangedMind,
+        None,
+    ));
+    icrc::set_unreachable(true);
+
+    as_caller(ADMIN);
+    assert!(matches!(
+        err(block_on(settle_refund(order.id))),
+        Error::PaymentFailed { .. }
+    ));
+
+    assert_eq!(super::order(order.id).status, OrderStatus::Cancelled);
+    as_caller(CLIENT);
+    assert_eq!(ok(get_order_refund(order.id)).settled_at, None);
+    assert!(no_calls_in_flight());
+
+    icrc::set_unreachable(false);
+    as_caller(ADMIN);
+    ok(block_on(settle_refund(order.id)));
+    assert_eq!(super::order(order.id).status, OrderStatus::Refunded);
+}
+
+#[test]
+fn delivery_splits_the_payment_along_the_price_breakdown() {
+    let (restaurant, client_id) = ledger_setup();
+    as_caller(ADMIN);
+    ok(update_pricing_rules(PricingRules {
+        delivery_fee_bands: vec![DeliveryFeeBand {
+            max_distance_km: 10.0,
+            fee: usd(500),
+        }],
+        service_fee_bps: 500,
+        default_tax_bps: 1_000,
+        ..PricingRules::default()
+    }));
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    as_caller(CLIENT);
+    let order = ok(create_order(OrderPayload {
+        tip: Some(usd(300)),
+        ..order_payload(client_id, &[(item.id, 2)])
+    }));
+    // 20.00 of food, 5.00 delivery, 1.00 service fee, 2.00 tax and a 3.00 tip
+    assert_eq!(order.total, usd(3_100));
+    icrc::approve(principal(CLIENT), 3_100);
+    ok(pay(order.id));
+    ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+    let courier = assign(order.id);
+    deliver(order.id);
+
+    let payouts: Vec<(LedgerEntryKind, Principal, Money)> = ledger_entries(order.id)
+        .into_iter()
+        .map(|entry| (entry.kind, entry.counterparty, entry.amount))
+        .collect();
+    assert_eq!(
+        payouts,
+        vec![
+            (LedgerEntryKind::Payment, principal(CLIENT), usd(3_100)),
+            (
+                LedgerEntryKind::CourierPayout,
+                courier.principal,
+                usd(300 + 50)
+            ),
+            (
+                LedgerEntryKind::RestaurantPayout,
+                restaurant.owner,
+                usd(2_000)
+            ),
+        ]
+    );
+}
+
+#[test]
+fn deleting_an_account_forgets_the_client_on_its_orders() {
+    let order = placed_order();
+    icrc::approve(principal(CLIENT), 2_000);
+    ok(pay(order.id));
+    as_caller(CLIENT);
+    ok(cancel_order(
+        order.id,
+        CancellationReason::ChangedMind,
+        Some("ordered by mistake".to_string()),
+    ));
+
+    // The refund is paid to the client's principal, so the account stays until it is settled
+    assert!(matches!(err(delete_my_account()), Error::Forbidden { .. }));
+    as_caller(ADMIN);
+    ok(block_on(settle_refund(order.id)));
+    as_caller(CLIENT);
+    ok(delete_my_account());
+
+    let cancellation = super::order(order.id).cancellation.unwrap();
+    assert_eq!(cancellation.cancelled_by, Principal::anonymous());
+    assert_eq!(cancellation.note, None);
+    let events: Vec<OrderEvent> = ORDER_EVENTS.with(|s| {
+        s.borrow()
+            .range((order.id, 0)..=(order.id, u64::MAX))
+            .map(|(_, event)| event)
+            .collect()
+    });
+    assert!(events
+        .iter()
+        .all(|event| event.actor != principal(CLIENT) && event.note.is_none()));
+    let entries = ledger_entries(order.id);
+    assert_eq!(entries.len(), 2);
+    assert!(entries.iter().all(|entry| {
+        entry.counterparty == Principal::anonymous() && entry.block_index.is_some()
+    }));
+}
+
+#[test]
+fn free_delivery_is_not_paid_out_or_charged_to_the_restaurant() {
+    let (restaurant, client_id) = ledger_setup();
+    as_caller(ADMIN);
+    ok(update_pricing_rules(PricingRules {
+        delivery_fee_bands: vec![DeliveryFeeBand {
+            max_distance_km: 10.0,
+            fee: usd(500),
+        }],
+        ..PricingRules::default()
+    }));
+    ok(create_promotion(promotion_payload(
+        "FREEDELIVERY",
+        Discount::FreeDelivery,
+    )));
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    as_caller(CLIENT);
+    let order = ok(create_order(OrderPayload {
+        tip: Some(usd(300)),
+        promo_code: Some("FREEDELIVERY".to_string()),
+        ..order_payload(client_id, &[(item.id, 2)])
+    }));
+    // 20.00 of food, 5.00 delivery waived by the promotion and a 3.00 tip
+    let breakdown = order.price_breakdown.clone().unwrap();
+    assert_eq!(breakdown.delivery_discount, Some(usd(500)));
+    assert_eq!(order.total, usd(2_300));
+    icrc::approve(principal(CLIENT), 2_300);
+    ok(pay(order.id));
+    ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+    let courier = assign(order.id);
+    deliver(order.id);
+
+    let payouts: Vec<(LedgerEntryKind, Principal, Money)> = ledger_entries(order.id)
+        .into_iter()
+        .skip(1)
+        .map(|entry| (entry.kind, entry.counterparty, entry.amount))
+        .collect();
+    assert_eq!(
+        payouts,
+        vec![
+            (LedgerEntryKind::CourierPayout, courier.principal, usd(300)),
+            (
+                LedgerEntryKind::RestaurantPayout,
+                restaurant.owner,
+                usd(2_000)
+            ),
+        ]
+    );
+}
@@ -0,0 +1,75 @@
+use super::*;
+
+fn eur(amount_minor: u64) -> Money {
+    Money::new(amount_minor, Currency::Eur)
+}
+
+fn is_invalid_payload(error: Error) -> bool {
+    matches!(error, Error::InvalidPayload { .. })
+}
+
+#[test]
+fn amounts_outside_the_base_currency_are_rejected_when_written() {
+    let (restaurant, client_id) = setup();
+    as_caller(OWNER);
+    let rejected = create_food_item(ItemPayload {
+        name: "Margherita".to_string(),
+        description: "Tomato and mozzarella".to_string(),
+        price: eur(1_000),
+        category: "pizza".to_string(),
+        restaurant_id: restaurant.id,
+        prep_minutes: 10,
+        dietary_tags: Vec::new(),
+    });
+    assert!(is_invalid_payload(err(rejected)));
+
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    as_caller(OWNER);
+    assert!(is_invalid_payload(err(update_food_item(
+        item.id,
+        ItemUpdate {
+            price: Some(eur(1_200)),
+            ..ItemUpdate::default()
+        }
+    ))));
+    assert_eq!(ok(get_food_item_by_id(item.id)).price, usd(1_000));
+
+    as_caller(CLIENT);
+    assert!(is_invalid_payload(err(create_order(OrderPayload {
+        tip: Some(eur(200)),
+        ..order_payload(client_id, &[(item.id, 1)])
+    }))));
+
+    as_caller(ADMIN);
+    assert!(is_invalid_payload(err(update_pricing_rules(
+        PricingRules {
+            delivery_fee_bands: vec![DeliveryFeeBand {
+                max_distance_km: 5.0,
+                fee: eur(300),
+            }],
+            ..PricingRules::default()
+        }
+    ))));
+    assert!(is_invalid_payload(err(update_pricing_rules(
+        PricingRules {
+            small_order_fees: vec![SmallOrderFee {
+                min_subtotal: eur(1_500),
+                fee: eur(200),
+            }],
+            ..PricingRules::default()
+        }
+    ))));
+    assert!(is_invalid_payload(err(create_promotion(
+        promotion_payload("EURO5", Discount::FixedAmount { amount: eur(500) })
+    ))));
+    assert!(is_invalid_payload(err(create_promotion(
+        PromotionPayload {
+            min_subtotal: Some(eur(2_000)),
+            ..promotion_payload("TENPERCENT", Discount::Percentage { bps: 1_000 })
+        }
+    ))));
+    ok(create_promotion(PromotionPayload {
+        min_subtotal: Some(usd(2_000)),
+        ..promotion_payload("TENPERCENT", Discount::Percentage { bps: 1_000 })
+    }));
+}
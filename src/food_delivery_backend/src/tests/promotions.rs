@@ -0,0 +1,232 @@
+use super::*;
+
+const OTHER_CLIENT: u8 = 6;
+const HOUR: u64 = 3_600_000_000_000;
+
+fn create(payload: PromotionPayload) -> Promotion {
+    as_caller(ADMIN);
+    ok(create_promotion(payload))
+}
+
+fn with_code(client_id: u64, items: &[(u64, u64)], code: &str) -> OrderPayload {
+    OrderPayload {
+        promo_code: Some(code.to_string()),
+        ..order_payload(client_id, items)
+    }
+}
+
+fn order_as(caller: u8, payload: OrderPayload) -> Result<Order, Error> {
+    as_caller(caller);
+    create_order(payload)
+}
+
+fn quote(payload: OrderPayload) -> PriceBreakdown {
+    as_caller(ADMIN);
+    ok(quote_order(payload))
+}
+
+fn is_rejected(result: Result<Order, Error>) -> bool {
+    matches!(err(result), Error::InvalidPromotion { .. })
+}
+
+fn cancel(caller: u8, order_id: u64) {
+    as_caller(caller);
+    ok(cancel_order(
+        order_id,
+        CancellationReason::ChangedMind,
+        None,
+    ));
+}
+
+fn other_client() -> u64 {
+    as_caller(OTHER_CLIENT);
+    ok(create_client(ClientPayload {
+        name: "Other client".to_string(),
+        address: "4 Main Street".to_string(),
+        phone: "555-0101".to_string(),
+        email: "other@example.com".to_string(),
+    }))
+    .id
+}
+
+fn ten_percent_tax() {
+    as_caller(ADMIN);
+    ok(update_pricing_rules(PricingRules {
+        default_tax_bps: 1_000,
+        category_tax_rates: vec![CategoryTaxRate {
+            category: "drinks".to_string(),
+            rate_bps: 0,
+        }],
+        ..PricingRules::default()
+    }));
+}
+
+#[test]
+fn discounted_lines_are_taxed_after_their_discount() {
+    let (restaurant, client_id) = setup();
+    ten_percent_tax();
+    let pizza = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let soda = add_item(restaurant.id, "Lemonade", "drinks", 500);
+    create(PromotionPayload {
+        categories: vec!["pizza".to_string()],
+        ..promotion_payload("PIZZA20", Discount::Percentage { bps: 2_000 })
+    });
+
+    let price = quote(with_code(
+        client_id,
+        &[(pizza.id, 1), (soda.id, 1)],
+        "PIZZA20",
+    ));
+
+    assert_eq!(price.subtotal, usd(1_500));
+    assert_eq!(price.discount, usd(200));
+    // 10% of the 8.00 left of the pizza, and no tax on drinks
+    assert_eq!(price.tax, usd(80));
+    assert_eq!(price.total, usd(1_380));
+}
+
+#[test]
+fn a_fixed_discount_is_shared_across_lines_before_tax() {
+    let (restaurant, client_id) = setup();
+    ten_percent_tax();
+    let items: Vec<Item> = [333, 333, 334]
+        .iter()
+        .map(|&price| add_item(restaurant.id, "Slice", "pizza", price))
+        .collect();
+    create(promotion_payload(
+        "FIVEOFF",
+        Discount::FixedAmount { amount: usd(500) },
+    ));
+    let lines: Vec<(u64, u64)> = items.iter().map(|item| (item.id, 1)).collect();
+
+    let price = quote(with_code(client_id, &lines, "FIVEOFF"));
+
+    // The lines keep 1.67, 1.66 and 1.67 after sharing the discount
+    assert_eq!(price.discount, usd(500));
+    assert_eq!(price.tax, usd(16 + 16 + 16));
+    assert_eq!(price.total, usd(1_000 - 500 + 48));
+}
+
+#[test]
+fn free_delivery_does_not_change_the_tax() {
+    let (restaurant, client_id) = setup();
+    as_caller(ADMIN);
+    ok(update_pricing_rules(PricingRules {
+        delivery_fee_bands: vec![DeliveryFeeBand {
+            max_distance_km: 10.0,
+            fee: usd(400),
+        }],
+        default_tax_bps: 1_000,
+        ..PricingRules::default()
+    }));
+    let pizza = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    create(promotion_payload("FREEDEL", Discount::FreeDelivery));
+
+    let price = quote(with_code(client_id, &[(pizza.id, 1)], "FREEDEL"));
+
+    assert_eq!(price.discount, usd(400));
+    assert_eq!(price.tax, usd(100));
+    assert_eq!(price.total, usd(1_100));
+}
+
+#[test]
+fn the_global_limit_is_shared_by_all_clients_and_released_on_cancel() {
+    let (restaurant, client_id) = setup();
+    let other_id = other_client();
+    let pizza = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let promotion = create(PromotionPayload {
+        max_redemptions: Some(1),
+        ..promotion_payload("ONCE", Discount::Percentage { bps: 1_000 })
+    });
+
+    let order = ok(order_as(
+        CLIENT,
+        with_code(client_id, &[(pizza.id, 1)], "ONCE"),
+    ));
+    assert_eq!(order.promotion_id, Some(promotion.id));
+    assert!(is_rejected(order_as(
+        OTHER_CLIENT,
+        with_code(other_id, &[(pizza.id, 1)], "ONCE")
+    )));
+
+    cancel(CLIENT, order.id);
+    as_caller(ADMIN);
+    assert_eq!(ok(get_promotion(promotion.id)).redemptions, 0);
+    assert!(PROMOTION_REDEMPTIONS
+        .with(|s| s.borrow().get(&(promotion.id, client_id)))
+        .is_none());
+    ok(order_as(
+        OTHER_CLIENT,
+        with_code(other_id, &[(pizza.id, 1)], "ONCE"),
+    ));
+    as_caller(ADMIN);
+    assert_eq!(ok(get_promotion(promotion.id)).redemptions, 1);
+}
+
+#[test]
+fn the_per_client_limit_does_not_affect_other_clients() {
+    let (restaurant, client_id) = setup();
+    let other_id = other_client();
+    let pizza = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    create(PromotionPayload {
+        max_redemptions_per_client: Some(1),
+        ..promotion_payload("WELCOME", Discount::Percentage { bps: 1_000 })
+    });
+
+    ok(order_as(
+        CLIENT,
+        with_code(client_id, &[(pizza.id, 1)], "WELCOME"),
+    ));
+    assert!(is_rejected(order_as(
+        CLIENT,
+        with_code(client_id, &[(pizza.id, 1)], "WELCOME")
+    )));
+    ok(order_as(
+        OTHER_CLIENT,
+        with_code(other_id, &[(pizza.id, 1)], "WELCOME"),
+    ));
+    // Ordering without the code is unaffected
+    ok(order_as(CLIENT, order_payload(client_id, &[(pizza.id, 1)])));
+}
+
+#[test]
+fn first_order_only_ignores_cancelled_orders() {
+    let (restaurant, client_id) = setup();
+    let pizza = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    create(PromotionPayload {
+        first_order_only: true,
+        ..promotion_payload("FIRST", Discount::Percentage { bps: 1_000 })
+    });
+
+    let earlier = place_order(client_id, &[(pizza.id, 1)]);
+    assert!(is_rejected(order_as(
+        CLIENT,
+        with_code(client_id, &[(pizza.id, 1)], "FIRST")
+    )));
+
+    cancel(CLIENT, earlier.id);
+    ok(order_as(
+        CLIENT,
+        with_code(client_id, &[(pizza.id, 1)], "FIRST"),
+    ));
+}
+
+#[test]
+fn codes_are_only_redeemable_within_their_window() {
+    let (restaurant, client_id) = setup();
+    let pizza = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let starts = env::time() + HOUR;
+    create(PromotionPayload {
+        valid_from: starts,
+        valid_until: Some(starts + HOUR),
+        ..promotion_payload("HAPPYHOUR", Discount::Percentage { bps: 1_000 })
+    });
+    let payload = || with_code(client_id, &[(pizza.id, 1)], "happyhour ");
+
+    assert!(is_rejected(order_as(CLIENT, payload())));
+    env::advance_time(HOUR);
+    let order = ok(order_as(CLIENT, payload()));
+    assert_eq!(order.total, usd(900));
+    env::advance_time(HOUR);
+    assert!(is_rejected(order_as(CLIENT, payload())));
+}
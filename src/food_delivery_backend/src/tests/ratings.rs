@@ -0,0 +1,110 @@
+use super::*;
+
+fn summary(item_id: u64) -> RatingSummary {
+    ok(get_rating_summary(item_id))
+}
+
+fn restaurant_rating(restaurant_id: u64) -> Option<RatingStats> {
+    RESTAURANT_STORAGE
+        .with(|s| s.borrow().get(&restaurant_id))
+        .and_then(|restaurant| restaurant.rating)
+}
+
+fn delete_review(caller: u8, review_id: u64) -> Result<String, Error> {
+    as_caller(caller);
+    delete_review_by_id(DeleteReviewPayload { review_id })
+}
+
+#[test]
+fn unrated_items_have_the_prior_as_bayesian_average() {
+    let (restaurant, _) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+
+    let rated = summary(item.id);
+    assert_eq!(rated.count, 0);
+    assert_eq!(rated.histogram, [0; 5]);
+    assert_eq!(rated.average, None);
+    assert_eq!(rated.bayesian_average, RATING_PRIOR_MEAN);
+}
+
+#[test]
+fn reviews_fill_the_histogram_and_move_the_averages() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let other = add_item(restaurant.id, "Calzone", "pizza", 1_200);
+    for rating in [5, 4, 5] {
+        let order = delivered_order(client_id, &[(item.id, 1), (other.id, 1)]);
+        review(order.id, item.id, rating);
+    }
+    let order = delivered_order(client_id, &[(other.id, 1)]);
+    review(order.id, other.id, 1);
+
+    let rated = summary(item.id);
+    assert_eq!(rated.count, 3);
+    assert_eq!(rated.sum, 14);
+    assert_eq!(rated.histogram, [0, 0, 0, 1, 2]);
+    assert_eq!(rated.average, Some(14.0 / 3.0));
+    // Three reviews weigh against five prior votes of three stars
+    assert_eq!(rated.bayesian_average, (15.0 + 14.0) / 8.0);
+
+    let restaurant_stats = restaurant_rating(restaurant.id).unwrap();
+    assert_eq!(restaurant_stats.count, 4);
+    assert_eq!(restaurant_stats.histogram, [1, 0, 0, 1, 2]);
+}
+
+#[test]
+fn deleting_a_review_takes_it_out_of_the_histogram_and_averages() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let first = delivered_order(client_id, &[(item.id, 1)]);
+    let kept = review(first.id, item.id, 2);
+    let second = delivered_order(client_id, &[(item.id, 1)]);
+    let deleted = review(second.id, item.id, 5);
+
+    assert!(delete_review(OWNER, deleted.id).is_err());
+    assert_eq!(summary(item.id).count, 2);
+    ok(delete_review(CLIENT, deleted.id));
+
+    let rated = summary(item.id);
+    assert_eq!(rated.count, 1);
+    let listed = ok(get_reviews_by_item_id_v2(item.id, Page::default()));
+    assert_eq!(listed.total, 1);
+    assert_eq!(listed.items[0].id, kept.id);
+    assert_eq!(rated.sum, kept.rating);
+    assert_eq!(rated.histogram, [0, 1, 0, 0, 0]);
+    assert_eq!(rated.average, Some(2.0));
+    assert_eq!(rated.bayesian_average, (15.0 + 2.0) / 6.0);
+    assert_eq!(
+        restaurant_rating(restaurant.id).unwrap().histogram,
+        [0, 1, 0, 0, 0]
+    );
+
+    // The order's item can be reviewed again once its review is gone
+    review(second.id, item.id, 3);
+    assert_eq!(summary(item.id).histogram, [0, 1, 1, 0, 0]);
+}
+
+#[test]
+fn review_comments_are_bounded_to_fit_a_review() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let order = delivered_order(client_id, &[(item.id, 1)]);
+    let payload = |comment: String| ReviewPayload {
+        item_id: item.id,
+        order_id: order.id,
+        rating: 4,
+        comment,
+    };
+    as_caller(CLIENT);
+    let too_long = "a".repeat(MAX_REVIEW_COMMENT_CHARS as usize + 1);
+    assert!(matches!(
+        err(create_review(payload(too_long))),
+        Error::InvalidPayload { .. }
+    ));
+    assert_eq!(summary(item.id).count, 0);
+
+    // The longest comment in four-byte characters still fits a review
+    let longest = "\u{1F355}".repeat(MAX_REVIEW_COMMENT_CHARS as usize);
+    let review = ok(create_review(payload(longest.clone())));
+    assert_eq!(review.comment, longest);
+}
@@ -0,0 +1,139 @@
+use super::*;
+
+const DAY: u64 = LOCATION_RETENTION_NANOS;
+
+// Take an order through delivery with a courier that reports a point on the way
+fn delivered_with_route(client_id: u64, item_id: u64) -> Order {
+    let order = place_order(client_id, &[(item_id, 1)]);
+    ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+    assign(order.id);
+    as_caller(COURIER);
+    ok(report_location(order.id, 45.0, 9.0, 10.0));
+    deliver(order.id);
+    order
+}
+
+fn route_len(order_id: u64) -> usize {
+    ORDER_LOCATIONS.with(|s| {
+        s.borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .count()
+    })
+}
+
+#[test]
+fn routes_are_pruned_in_batches_once_their_retention_has_passed() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let orders: Vec<Order> = (0..=LOCATION_PRUNE_BATCH)
+        .map(|_| delivered_with_route(client_id, item.id))
+        .collect();
+
+    // Routes are kept for the retention window
+    prune_order_locations();
+    assert!(orders.iter().all(|order| route_len(order.id) == 1));
+
+    env::advance_time(DAY);
+    let recent = delivered_with_route(client_id, item.id);
+
+    // Each tick prunes at most one batch, oldest completions first
+    prune_order_locations();
+    let (pruned, kept) = orders.split_at(LOCATION_PRUNE_BATCH);
+    assert!(pruned.iter().all(|order| route_len(order.id) == 0));
+    assert_eq!(route_len(kept[0].id), 1);
+
+    prune_order_locations();
+    assert_eq!(route_len(kept[0].id), 0);
+    assert_eq!(route_len(recent.id), 1);
+    assert_eq!(ROUTE_PRUNE_QUEUE.with(|s| s.borrow().len()), 1);
+}
+
+#[test]
+fn routes_of_orders_completed_before_the_queue_are_queued_on_upgrade() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let order = delivered_with_route(client_id, item.id);
+    ROUTE_PRUNE_QUEUE.with(|s| {
+        s.borrow_mut()
+            .remove(&(order_completed_at(order.id).unwrap(), order.id))
+    });
+
+    backfill_indexes();
+    env::advance_time(DAY);
+    prune_order_locations();
+
+    assert_eq!(route_len(order.id), 0);
+}
+
+// Place an order for delivery a short way east of the restaurant, which stands at (0, 0)
+fn order_for_delivery(client_id: u64, item_id: u64) -> (Order, Location) {
+    let destination = Location {
+        latitude: 0.0,
+        longitude: 0.1,
+    };
+    as_caller(CLIENT);
+    let order = ok(create_order(OrderPayload {
+        delivery_location: Some(destination),
+        ..order_payload(client_id, &[(item_id, 1)])
+    }));
+    (order, destination)
+}
+
+fn eta(order_id: u64) -> OrderEta {
+    as_caller(CLIENT);
+    ok(get_order_eta(order_id))
+}
+
+#[test]
+fn the_eta_of_a_placed_order_covers_preparation_and_the_trip_from_the_restaurant() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let (order, destination) = order_for_delivery(client_id, item.id);
+    let from_restaurant = Location {
+        latitude: restaurant.latitude,
+        longitude: restaurant.longitude,
+    }
+    .distance_km(&destination);
+
+    let estimate = eta(order.id);
+    assert_eq!(estimate.remaining_distance_km, from_restaurant);
+    assert_eq!(
+        estimate.estimated_arrival,
+        env::time() + 10 * NANOS_PER_MINUTE + travel_nanos(from_restaurant, DEFAULT_SPEED_KMH)
+    );
+}
+
+#[test]
+fn the_eta_of_an_order_on_its_way_follows_the_reported_position() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let (order, destination) = order_for_delivery(client_id, item.id);
+    ok(set_status(OWNER, order.id, OrderStatus::Accepted));
+    let courier = assign(order.id);
+    for status in [OrderStatus::Preparing, OrderStatus::ReadyForPickup] {
+        ok(set_status(OWNER, order.id, status));
+    }
+    ok(set_status(COURIER, order.id, OrderStatus::PickedUp));
+
+    as_caller(COURIER);
+    ok(report_location(order.id, 0.0, 0.05, 10.0));
+    let halfway = Location {
+        latitude: 0.0,
+        longitude: 0.05,
+    }
+    .distance_km(&destination);
+    let estimate = eta(order.id);
+    assert_eq!(estimate.remaining_distance_km, halfway);
+    assert_eq!(
+        estimate.estimated_arrival,
+        env::time() + travel_nanos(halfway, courier.vehicle.average_speed_kmh())
+    );
+
+    // Delivered orders have no ETA any more
+    ok(set_status(COURIER, order.id, OrderStatus::Delivered));
+    as_caller(CLIENT);
+    assert!(matches!(
+        err(get_order_eta(order.id)),
+        Error::NotFound { .. }
+    ));
+}
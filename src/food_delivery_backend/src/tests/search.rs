@@ -0,0 +1,58 @@
+use super::*;
+
+fn search(query: SearchQuery) -> Result<SearchResult, Error> {
+    as_caller(CLIENT);
+    search_food_items(query)
+}
+
+fn ids(result: &SearchResult) -> Vec<u64> {
+    result.hits.iter().map(|hit| hit.item.id).collect()
+}
+
+#[test]
+fn text_without_words_is_rejected() {
+    let (restaurant, _) = setup();
+    add_item(restaurant.id, "Margherita", "pizza", 1_000);
+
+    for text in ["", "  ", "-- !?"] {
+        assert!(matches!(
+            err(search(SearchQuery {
+                text: Some(text.to_string()),
+                ..SearchQuery::default()
+            })),
+            Error::InvalidPayload { .. }
+        ));
+    }
+}
+
+#[test]
+fn search_pages_follow_the_sort_order() {
+    let (restaurant, _) = setup();
+    let cheap = add_item(restaurant.id, "Margherita", "pizza", 800);
+    let dear = add_item(restaurant.id, "Calzone", "pizza", 1_400);
+    let middle = add_item(restaurant.id, "Marinara", "pizza", 1_000);
+    let query = |start_after| SearchQuery {
+        category: Some("pizza".to_string()),
+        sort_by: Some(SearchSort::PriceDescending),
+        page: Page {
+            start_after,
+            limit: 2,
+        },
+        ..SearchQuery::default()
+    };
+
+    let first = ok(search(query(None)));
+    assert_eq!(ids(&first), vec![dear.id, middle.id]);
+    assert_eq!(first.total, 3);
+    let rest = ok(search(query(first.next_cursor)));
+    assert_eq!(ids(&rest), vec![cheap.id]);
+    assert_eq!(rest.next_cursor, None);
+
+    // A cursor item that stopped matching cannot place the next page
+    as_caller(OWNER);
+    ok(delete_food_item_by_id(middle.id));
+    assert!(matches!(
+        err(search(query(first.next_cursor))),
+        Error::InvalidPayload { .. }
+    ));
+}
@@ -0,0 +1,118 @@
+use super::*;
+
+fn is_invalid_payload(error: Error) -> bool {
+    matches!(error, Error::InvalidPayload { .. })
+}
+
+// Text of the given length in four-byte characters, the largest it can encode to
+fn pizzas(chars: u64) -> String {
+    "\u{1F355}".repeat(chars as usize)
+}
+
+#[test]
+fn the_largest_restaurant_fits_its_storage() {
+    let (restaurant, _) = setup();
+    let payload = RestaurantPayload {
+        name: pizzas(MAX_RESTAURANT_NAME_CHARS),
+        address: pizzas(MAX_RESTAURANT_ADDRESS_CHARS),
+        latitude: -90.0,
+        longitude: 180.0,
+        opening_hours: (0..MAX_OPENING_HOURS)
+            .map(|entry| OpeningHours {
+                day: (entry % 7) as u8,
+                opens_at: 0,
+                closes_at: 24 * 60,
+            })
+            .collect(),
+    };
+    as_caller(OWNER);
+    let mut more_hours = payload.opening_hours.clone();
+    more_hours.push(more_hours[0].clone());
+    for oversized in [
+        RestaurantPayload {
+            name: format!("{}a", payload.name),
+            ..payload.clone()
+        },
+        RestaurantPayload {
+            address: format!("{}a", payload.address),
+            ..payload.clone()
+        },
+        RestaurantPayload {
+            opening_hours: more_hours,
+            ..payload.clone()
+        },
+    ] {
+        assert!(is_invalid_payload(err(update_restaurant(
+            restaurant.id,
+            oversized
+        ))));
+    }
+
+    let updated = ok(update_restaurant(restaurant.id, payload.clone()));
+    let stored = ok(get_restaurant_by_id(restaurant.id));
+    assert_eq!(stored.name, payload.name);
+    assert_eq!(stored.opening_hours.len(), MAX_OPENING_HOURS as usize);
+    assert!(updated.to_bytes().len() <= Restaurant::MAX_SIZE as usize);
+}
+
+#[test]
+fn the_largest_courier_fits_its_storage() {
+    add_role(principal(COURIER), Role::Courier);
+    as_caller(COURIER);
+    let payload = |name: String| CourierPayload {
+        name,
+        vehicle: VehicleType::Motorbike,
+    };
+    assert!(is_invalid_payload(err(register_courier(payload(format!(
+        "{}a",
+        pizzas(MAX_COURIER_NAME_CHARS)
+    ))))));
+
+    ok(register_courier(payload(pizzas(MAX_COURIER_NAME_CHARS))));
+    let courier = ok(go_online(Some(Location {
+        latitude: -90.0,
+        longitude: 180.0,
+    })));
+    assert_eq!(courier.name, pizzas(MAX_COURIER_NAME_CHARS));
+    assert!(courier.to_bytes().len() <= Courier::MAX_SIZE as usize);
+}
+
+#[test]
+fn the_largest_client_fits_its_storage() {
+    let (_, client_id) = setup();
+    let update = ClientUpdate {
+        name: Some(pizzas(MAX_CLIENT_NAME_CHARS)),
+        address: Some(pizzas(MAX_CLIENT_ADDRESS_CHARS)),
+        phone: Some(pizzas(MAX_CLIENT_PHONE_CHARS)),
+        email: Some(pizzas(MAX_CLIENT_EMAIL_CHARS)),
+    };
+    let longer = |text: &Option<String>| text.as_ref().map(|text| format!("{}a", text));
+    as_caller(CLIENT);
+    for oversized in [
+        ClientUpdate {
+            name: longer(&update.name),
+            ..update.clone()
+        },
+        ClientUpdate {
+            address: longer(&update.address),
+            ..update.clone()
+        },
+        ClientUpdate {
+            phone: longer(&update.phone),
+            ..update.clone()
+        },
+        ClientUpdate {
+            email: longer(&update.email),
+            ..update.clone()
+        },
+    ] {
+        assert!(is_invalid_payload(err(update_client(client_id, oversized))));
+    }
+
+    ok(update_client(client_id, update.clone()));
+    let mut client = CLIENT_STORAGE.with(|s| s.borrow().get(&client_id)).unwrap();
+    assert_eq!(Some(client.email.clone()), update.email);
+    client.order_ids = vec![u64::MAX; MAX_CLIENT_ORDER_IDS];
+    client.deleted_at = Some(u64::MAX);
+    assert!(client.to_bytes().len() <= Client::MAX_SIZE as usize);
+}
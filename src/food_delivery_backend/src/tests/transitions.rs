@@ -0,0 +1,237 @@
+use super::*;
+use OrderStatus::*;
+
+const STATUSES: [OrderStatus; 10] = [
+    Placed,
+    Paid,
+    Accepted,
+    Preparing,
+    ReadyForPickup,
+    PickedUp,
+    InTransit,
+    Delivered,
+    Cancelled,
+    Refunded,
+];
+
+const ALLOWED: [(OrderStatus, OrderStatus); 18] = [
+    (Placed, Accepted),
+    (Placed, Paid),
+    (Placed, Cancelled),
+    (Paid, Accepted),
+    (Paid, Cancelled),
+    (Accepted, Preparing),
+    (Accepted, Cancelled),
+    (Preparing, ReadyForPickup),
+    (Preparing, Cancelled),
+    (ReadyForPickup, PickedUp),
+    (ReadyForPickup, Cancelled),
+    (PickedUp, InTransit),
+    (PickedUp, Delivered),
+    (PickedUp, Cancelled),
+    (InTransit, Delivered),
+    (InTransit, Cancelled),
+    (Delivered, Refunded),
+    (Cancelled, Refunded),
+];
+
+fn order_in(status: OrderStatus) -> Order {
+    Order {
+        id: 1,
+        status,
+        ..Order::default()
+    }
+}
+
+#[test]
+fn transition_table_allows_exactly_the_listed_moves() {
+    for from in STATUSES {
+        for to in STATUSES {
+            assert_eq!(
+                from.can_transition_to(to),
+                ALLOWED.contains(&(from, to)),
+                "{:?} -> {:?}",
+                from,
+                to
+            );
+        }
+    }
+}
+
+#[test]
+fn refunded_orders_cannot_move() {
+    for to in STATUSES {
+        assert!(!Refunded.can_transition_to(to), "Refunded -> {:?}", to);
+    }
+}
+
+#[test]
+fn set_status_applies_allowed_moves_and_tracks_delivery() {
+    let mut order = order_in(InTransit);
+    assert!(order.set_status(Delivered).is_ok());
+    assert_eq!(order.status, Delivered);
+    assert!(order.delivered);
+
+    assert!(order.set_status(Refunded).is_ok());
+    assert_eq!(order.status, Refunded);
+    assert!(!order.delivered);
+}
+
+#[test]
+fn set_status_rejects_forbidden_moves_and_keeps_the_status() {
+    for (from, to) in [
+        (Placed, Delivered),
+        (Preparing, Accepted),
+        (Delivered, Cancelled),
+        (Cancelled, Placed),
+        (Paid, Paid),
+    ] {
+        let mut order = order_in(from);
+        assert!(
+            matches!(err(order.set_status(to)), Error::InvalidTransition { .. }),
+            "{:?} -> {:?}",
+            from,
+            to
+        );
+        assert_eq!(order.status, from);
+    }
+}
+
+#[test]
+fn update_order_status_follows_the_table() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let order = place_order(client_id, &[(item.id, 1)]);
+
+    assert!(matches!(
+        err(set_status(OWNER, order.id, ReadyForPickup)),
+        Error::InvalidTransition { .. }
+    ));
+    ok(set_status(OWNER, order.id, Accepted));
+    ok(set_status(OWNER, order.id, Preparing));
+    assert!(matches!(
+        err(set_status(OWNER, order.id, Accepted)),
+        Error::InvalidTransition { .. }
+    ));
+    assert_eq!(super::order(order.id).status, Preparing);
+
+    as_caller(CLIENT);
+    let timeline: Vec<OrderStatus> = ok(get_order_timeline(order.id, Page::default()))
+        .items
+        .iter()
+        .map(|event| event.status)
+        .collect();
+    assert_eq!(timeline, vec![Placed, Accepted, Preparing]);
+
+    // The cursor of a timeline page is the sequence number of its last event
+    let first = ok(get_order_timeline(
+        order.id,
+        Page {
+            start_after: None,
+            limit: 2,
+        },
+    ));
+    assert_eq!(first.items.len(), 2);
+    let rest = ok(get_order_timeline(
+        order.id,
+        Page {
+            start_after: first.next_cursor,
+            limit: 2,
+        },
+    ));
+    assert_eq!(rest.items.len(), 1);
+    assert_eq!(rest.items[0].status, Preparing);
+    assert_eq!(rest.next_cursor, None);
+}
+
+#[test]
+fn status_notes_are_bounded_to_fit_the_timeline() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let order = place_order(client_id, &[(item.id, 1)]);
+    as_caller(OWNER);
+    let too_long = "a".repeat(MAX_NOTE_CHARS as usize + 1);
+    assert!(matches!(
+        err(update_order_status(order.id, Accepted, Some(too_long))),
+        Error::InvalidPayload { .. }
+    ));
+    assert_eq!(super::order(order.id).status, Placed);
+
+    // The longest note in four-byte characters still fits an event
+    let longest = "\u{1F355}".repeat(MAX_NOTE_CHARS as usize);
+    ok(update_order_status(
+        order.id,
+        Accepted,
+        Some(longest.clone()),
+    ));
+    as_caller(CLIENT);
+    let timeline = ok(get_order_timeline(order.id, Page::default())).items;
+    assert_eq!(timeline.last().unwrap().note, Some(longest));
+}
+
+#[test]
+fn cancellation_notes_are_bounded_like_status_notes() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let order = place_order(client_id, &[(item.id, 1)]);
+    as_caller(CLIENT);
+    let too_long = "a".repeat(MAX_NOTE_CHARS as usize + 1);
+    assert!(matches!(
+        err(cancel_order(
+            order.id,
+            CancellationReason::ChangedMind,
+            Some(too_long)
+        )),
+        Error::InvalidPayload { .. }
+    ));
+    assert_eq!(super::order(order.id).status, Placed);
+
+    let longest = "\u{1F355}".repeat(MAX_NOTE_CHARS as usize);
+    let cancelled = ok(cancel_order(
+        order.id,
+        CancellationReason::ChangedMind,
+        Some(longest.clone()),
+    ));
+    assert_eq!(cancelled.cancellation.unwrap().note, Some(longest));
+}
+
+#[test]
+fn cancelling_an_order_releases_its_courier() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let order = place_order(client_id, &[(item.id, 1)]);
+    ok(set_status(OWNER, order.id, Accepted));
+    let courier = assign(order.id);
+
+    as_caller(OWNER);
+    let cancelled = ok(cancel_order(
+        order.id,
+        CancellationReason::ItemsUnavailable,
+        Some("out of dough".to_string()),
+    ));
+
+    assert!(cancelled.assignment.is_none());
+    as_caller(COURIER);
+    assert!(ok(get_orders_for_courier(courier.id, Page::default()))
+        .items
+        .is_empty());
+    assert!(report_location(order.id, 45.0, 9.0, 10.0).is_err());
+    as_caller(CLIENT);
+    let timeline = ok(get_order_timeline(order.id, Page::default())).items;
+    let cancellations: Vec<&OrderEvent> = timeline
+        .iter()
+        .filter(|event| event.status == Cancelled)
+        .collect();
+    assert_eq!(cancellations.len(), 1);
+    assert_eq!(
+        cancellations[0].note,
+        Some(format!(
+            "out of dough (courier id: {} released)",
+            courier.id
+        ))
+    );
+    assert_eq!(
+        cancelled.cancellation.unwrap().note,
+        Some("out of dough".to_string())
+    );
+}
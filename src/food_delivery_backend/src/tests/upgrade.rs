@@ -0,0 +1,251 @@
+use super::*;
+
+// Store a value in the raw layout of an earlier version, as an upgrade finds it
+fn store_raw<K: BoundedStorable + Ord + Clone, V: BoundedStorable>(
+    memory_id: u8,
+    key: K,
+    bytes: Vec<u8>,
+) {
+    let mut stored: StableBTreeMap<K, RawBytes<V>, Memory> =
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))));
+    stored.insert(key, RawBytes(bytes, PhantomData));
+}
+
+fn eur(amount_minor: u64) -> Money {
+    Money::new(amount_minor, Currency::Eur)
+}
+
+#[test]
+fn plain_amounts_are_rewritten_in_the_base_currency() {
+    set_base_currency(Currency::Eur);
+    let plain_item = PlainPriceItem {
+        id: 1,
+        name: "Margherita".to_string(),
+        description: "Tomato and mozzarella".to_string(),
+        price: 900,
+        category: "pizza".to_string(),
+        restaurant_id: None,
+        prep_minutes: None,
+        dietary_tags: None,
+        updated_at: None,
+        availability: None,
+        archived_at: None,
+        rating: None,
+    };
+    store_raw::<u64, Item>(4, 1, Encode!(&plain_item).unwrap());
+    let current_item = Item {
+        id: 2,
+        price: eur(1_100),
+        ..Item::from(plain_item)
+    };
+    store_raw::<u64, Item>(4, 2, current_item.to_bytes().into_owned());
+    let plain_order = PlainAmountOrder {
+        id: 3,
+        client_id: 7,
+        lines: vec![PlainAmountOrderLine {
+            item_id: 1,
+            name: "Margherita".to_string(),
+            unit_price: 900,
+            quantity: 2,
+            line_total: 1_800,
+        }],
+        total: 1_800,
+        status: OrderStatus::Cancelled,
+        delivered: false,
+        restaurant_id: None,
+        assignment: None,
+        delivery_location: None,
+        cancellation: None,
+        amount_captured: Some(1_800),
+    };
+    store_raw::<u64, Order>(14, 3, Encode!(&plain_order).unwrap());
+    let plain_refund = PlainAmountRefund {
+        order_id: 3,
+        client_id: 7,
+        amount: 1_800,
+        reason: CancellationReason::ChangedMind,
+        created_at: 1,
+        settled_at: None,
+    };
+    store_raw::<u64, Refund>(24, 3, Encode!(&plain_refund).unwrap());
+    let plain_entry = PlainAmountLedgerEntry {
+        order_id: 3,
+        kind: LedgerEntryKind::Payment,
+        counterparty: principal(CLIENT),
+        amount: 1_800,
+        block_index: Some(Nat::from(1u64)),
+        created_at: 1,
+    };
+    store_raw::<(u64, u64), LedgerEntry>(25, (3, 0), Encode!(&plain_entry).unwrap());
+
+    rewrite_plain_amounts();
+
+    let items: Vec<Money> =
+        ITEM_STORAGE.with(|s| s.borrow().iter().map(|(_, i)| i.price).collect());
+    assert_eq!(items, vec![eur(900), eur(1_100)]);
+    let order = order(3);
+    assert_eq!(order.total, eur(1_800));
+    assert_eq!(order.lines[0].unit_price, eur(900));
+    assert_eq!(order.amount_captured, Some(eur(1_800)));
+    let refund = REFUND_STORAGE.with(|s| s.borrow().get(&3)).unwrap();
+    assert_eq!(refund.amount, eur(1_800));
+    let entry = LEDGER_ENTRIES.with(|s| s.borrow().get(&(3, 0))).unwrap();
+    assert_eq!(entry.amount, eur(1_800));
+}
+
+#[test]
+fn legacy_orders_are_migrated_and_indexed_on_upgrade() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    // An order placed since the indexes exist, so none of them is empty any more
+    let placed = place_order(client_id, &[(item.id, 1)]);
+    for (id, status, delivered) in [(1_000, "delivered", true), (1_001, "cancelled", false)] {
+        let legacy = LegacyOrder {
+            id,
+            client_id,
+            items: HashMap::from([(item.id, 2)]),
+            total: 2_000,
+            status: status.to_string(),
+            delivered,
+        };
+        LEGACY_ORDER_STORAGE.with(|s| {
+            s.borrow_mut()
+                .insert(id, StoredOrderBytes(Encode!(&legacy).unwrap()))
+        });
+    }
+
+    as_caller(ADMIN);
+    migrate_stored_data(None);
+
+    assert!(LEGACY_ORDER_STORAGE.with(|s| s.borrow().is_empty()));
+    let delivered = order(1_000);
+    assert_eq!(delivered.status, OrderStatus::Delivered);
+    assert_eq!(delivered.lines[0].name, "Margherita");
+    assert_eq!(delivered.lines[0].unit_price, usd(1_000));
+    assert_eq!(order(1_001).status, OrderStatus::Cancelled);
+    assert_eq!(
+        ITEM_ORDER_COUNTS.with(|s| s.borrow().get(&item.id)),
+        Some(1 + 2 + 2)
+    );
+    as_caller(CLIENT);
+    let listed: Vec<u64> = ok(get_my_orders(Page::default()))
+        .items
+        .iter()
+        .map(|order| order.id)
+        .collect();
+    assert_eq!(listed, vec![placed.id, 1_000, 1_001]);
+    let client = CLIENT_STORAGE.with(|s| s.borrow().get(&client_id)).unwrap();
+    assert_eq!(client.order_ids, vec![1_001, 1_000, placed.id]);
+    as_caller(OWNER);
+    let delivered_ids: Vec<u64> = ok(get_orders_by_status(
+        OrderStatus::Delivered,
+        Page::default(),
+    ))
+    .items
+    .iter()
+    .map(|order| order.id)
+    .collect();
+    assert_eq!(delivered_ids, vec![1_000]);
+
+    // A second upgrade finds nothing left to move or count
+    as_caller(ADMIN);
+    migrate_stored_data(None);
+    assert_eq!(
+        ITEM_ORDER_COUNTS.with(|s| s.borrow().get(&item.id)),
+        Some(5)
+    );
+}
+
+#[test]
+fn client_order_index_is_built_on_upgrade() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let first = place_order(client_id, &[(item.id, 1)]);
+    let second = place_order(client_id, &[(item.id, 1)]);
+    // Orders stored before the index existed
+    CLIENT_ORDER_INDEX.with(|s| {
+        s.borrow_mut().remove(&(client_id, first.id));
+        s.borrow_mut().remove(&(client_id, second.id));
+    });
+
+    as_caller(ADMIN);
+    migrate_stored_data(None);
+
+    assert_eq!(client_order_ids(client_id), vec![first.id, second.id]);
+    let client = CLIENT_STORAGE.with(|s| s.borrow().get(&client_id)).unwrap();
+    assert_eq!(client.order_ids, vec![second.id, first.id]);
+}
+
+#[test]
+fn stored_data_is_migrated_once_per_layout() {
+    let (restaurant, client_id) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let order = place_order(client_id, &[(item.id, 1)]);
+
+    as_caller(ADMIN);
+    migrate_stored_data(None);
+    assert_eq!(get_config().layout_version, Some(STORAGE_LAYOUT_VERSION));
+
+    // Later upgrades leave the stored data alone, even where an index looks unbuilt
+    CLIENT_ORDER_INDEX.with(|s| s.borrow_mut().remove(&(client_id, order.id)));
+    migrate_stored_data(None);
+    assert!(client_order_ids(client_id).is_empty());
+}
+
+// The layout of reviews stored before they recorded their author and order
+#[derive(candid::CandidType)]
+struct AnonymousReview {
+    id: u64,
+    client_id: u64,
+    item_id: u64,
+    rating: u64,
+    comment: String,
+}
+
+#[test]
+fn reviews_stored_under_client_zero_have_no_author_after_upgrade() {
+    let (restaurant, _) = setup();
+    let item = add_item(restaurant.id, "Margherita", "pizza", 1_000);
+    let anonymous = AnonymousReview {
+        id: 500,
+        client_id: 0,
+        item_id: item.id,
+        rating: 4,
+        comment: "Nice".to_string(),
+    };
+    store_raw::<u64, Review>(3, 500, Encode!(&anonymous).unwrap());
+    // A verified review of a real client with id 0
+    let verified = Review {
+        id: 501,
+        client_id: Some(0),
+        item_id: item.id,
+        rating: 5,
+        comment: "Great".to_string(),
+        order_id: Some(42),
+    };
+    store_raw::<u64, Review>(3, 501, verified.to_bytes().into_owned());
+
+    as_caller(ADMIN);
+    migrate_stored_data(None);
+
+    let review = |id| REVIEW_STORAGE.with(|s| s.borrow().get(&id)).unwrap();
+    assert_eq!(review(500).client_id, None);
+    assert_eq!(review(501).client_id, Some(0));
+    assert_eq!(
+        CLIENT_REVIEW_INDEX.with(|s| index_ids(&s.borrow(), 0)),
+        vec![501]
+    );
+    assert_eq!(
+        ITEM_REVIEW_INDEX.with(|s| index_ids(&s.borrow(), item.id)),
+        vec![500, 501]
+    );
+
+    // Reviews without an author are left to admins
+    as_caller(CLIENT);
+    assert!(matches!(
+        err(delete_review_by_id(DeleteReviewPayload { review_id: 500 })),
+        Error::Unauthorized { .. }
+    ));
+    as_caller(ADMIN);
+    ok(delete_review_by_id(DeleteReviewPayload { review_id: 500 }));
+}